@@ -0,0 +1,72 @@
+#!/usr/bin/env python3
+# A minimal in-memory lobby server for trying out public games locally.
+# Usage: python3 stand_in.py [port]
+import json
+import sys
+import time
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+from threading import Lock
+from urllib.parse import parse_qs, urlparse
+
+# Rooms that haven't sent a heartbeat in this many seconds are removed
+ROOM_TTL = 30
+
+rooms = {}
+last_seen = {}
+lock = Lock()
+
+
+def expire():
+    now = time.time()
+    for name in [name for name, seen in last_seen.items() if now - seen > ROOM_TTL]:
+        rooms.pop(name, None)
+        last_seen.pop(name, None)
+
+
+class Handler(BaseHTTPRequestHandler):
+    def reply(self, status, body=None):
+        data = json.dumps(body).encode() if body is not None else b""
+        self.send_response(status)
+        self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", str(len(data)))
+        self.end_headers()
+        self.wfile.write(data)
+
+    def do_GET(self):
+        url = urlparse(self.path)
+        if url.path != "/rooms":
+            return self.reply(404)
+        rom_hash = parse_qs(url.query).get("rom_hash", [None])[0]
+        with lock:
+            expire()
+            listed = [room for room in rooms.values() if rom_hash in (None, room["rom_hash"])]
+        self.reply(200, listed)
+
+    def do_POST(self):
+        if urlparse(self.path).path != "/rooms":
+            return self.reply(404)
+        length = int(self.headers.get("Content-Length", 0))
+        room = json.loads(self.rfile.read(length))
+        with lock:
+            # Keep the original creation time when the host sends a heartbeat
+            if room["room_name"] in rooms:
+                room["created_at"] = rooms[room["room_name"]]["created_at"]
+            rooms[room["room_name"]] = room
+            last_seen[room["room_name"]] = time.time()
+        self.reply(200)
+
+    def do_DELETE(self):
+        path = urlparse(self.path).path
+        if not path.startswith("/rooms/"):
+            return self.reply(404)
+        name = path[len("/rooms/"):]
+        with lock:
+            rooms.pop(name, None)
+            last_seen.pop(name, None)
+        self.reply(200)
+
+
+if __name__ == "__main__":
+    port = int(sys.argv[1]) if len(sys.argv) > 1 else 8080
+    print(f"Lobby listening on port {port}")
+    ThreadingHTTPServer(("", port), Handler).serve_forever()
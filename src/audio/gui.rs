@@ -1,21 +1,30 @@
-use crate::{main_view::gui::GuiComponent, settings::Settings};
-use egui::{Slider, Ui};
+use std::{sync::mpsc::Sender, time::Duration};
+
+use crate::{
+    emulation::EmulatorCommand,
+    main_view::gui::{GuiComponent, GuiEvent},
+    settings::Settings,
+};
+use egui::{Grid, Slider, Ui};
 
 use super::{
     //debug::{AudioStat, AudioStats},
+    mixer::AudioChannel,
     Audio,
 };
 
 pub struct AudioGui {
     pub audio: Audio,
+    emulator_tx: Sender<EmulatorCommand>,
     // #[cfg(feature = "debug")]
     // stats: AudioStats,
 }
 
 impl AudioGui {
-    pub fn new(audio: Audio) -> Self {
+    pub fn new(audio: Audio, emulator_tx: Sender<EmulatorCommand>) -> Self {
         Self {
             audio,
+            emulator_tx,
             //stats: AudioStats::new(),
         }
     }
@@ -63,28 +72,35 @@ impl GuiComponent for AudioGui {
         // if let Some(tx) = &instance.stream.tx {
         //     self.stats.push_stat(AudioStat::new(tx.len()));
         // }
+    }
 
-        self.audio.sync_audio_devices();
+    fn handle_event(&mut self, gui_event: &GuiEvent) {
+        if let GuiEvent::Audio(audio_event) = gui_event {
+            log::debug!("Audio event: {audio_event:?}");
+            self.audio.sync_output_device();
+        }
     }
 
     fn ui(&mut self, ui: &mut Ui) {
         // #[cfg(feature = "debug")]
         // Self::stats_ui(ui, &self.stats);
-        let available_device_names =
-            Audio::get_available_output_device_names_for_subsystem(&self.audio.audio_subsystem);
-        let new_device = {
+        let available_device_names = self.audio.get_available_output_device_names();
+        let (new_device, new_latency) = {
             let mut new_device = None;
+            let mut new_latency = None;
             let audio_settings = &mut Settings::current_mut().audio;
             ui.horizontal(|ui| {
                 ui.label("Output");
                 let selected_device = &mut audio_settings.output_device;
-                if selected_device.is_none() {
-                    *selected_device = self.audio.get_default_device_name();
-                }
-                if let Some(selected_text) = selected_device.as_deref_mut() {
+                // Show the default device if the preferred one isn't available (it's switched back to when it is)
+                let selected_text = selected_device
+                    .clone()
+                    .filter(|name| available_device_names.contains(name))
+                    .or_else(|| self.audio.get_default_device_name());
+                if let Some(selected_text) = selected_text {
                     egui::ComboBox::from_id_source("audio-output")
                         .width(160.0)
-                        .selected_text(selected_text.to_string())
+                        .selected_text(selected_text)
                         .show_ui(ui, |ui| {
                             for name in available_device_names {
                                 if ui
@@ -107,11 +123,68 @@ impl GuiComponent for AudioGui {
                 ui.add(Slider::new(&mut audio_settings.volume, 0..=100).suffix("%"));
             });
 
-            new_device
+            ui.horizontal(|ui| {
+                ui.label("Latency");
+                let response =
+                    ui.add(Slider::new(&mut audio_settings.latency, 1..=200).suffix("ms"));
+                // Only rebuild the stream when the user lets go of the slider
+                if response.drag_stopped() || (response.changed() && !response.dragged()) {
+                    new_latency = Some(Duration::from_millis(audio_settings.latency as u64));
+                }
+            });
+            ui.label(format!(
+                "Buffer underruns: {}",
+                self.audio.stream.underruns()
+            ))
+            .on_hover_text(
+                "If this keeps increasing (and you hear crackling) try a higher latency",
+            );
+
+            ui.collapsing("Mixer", |ui| {
+                let mut mixer_settings = audio_settings.get_mixer().clone();
+                let mut changed = false;
+                Grid::new("audio-mixer")
+                    .num_columns(3)
+                    .spacing([10.0, 4.0])
+                    .show(ui, |ui| {
+                        for channel in AudioChannel::ALL {
+                            let channel_settings = mixer_settings.lookup(&channel);
+                            ui.label(channel.to_string());
+                            changed |= ui
+                                .add_enabled(
+                                    !channel_settings.muted,
+                                    Slider::new(&mut channel_settings.volume, 0..=100).suffix("%"),
+                                )
+                                .changed();
+                            changed |= ui.checkbox(&mut channel_settings.muted, "Mute").changed();
+                            ui.end_row();
+                        }
+                    });
+                if changed {
+                    let _ = self
+                        .emulator_tx
+                        .send(EmulatorCommand::SetMixer(mixer_settings.clone()));
+                    audio_settings.mixer = Some(mixer_settings);
+                }
+            });
+
+            (new_device, new_latency)
         };
         if let Some(new_device) = new_device {
             self.audio.stream.set_output_device(Some(new_device));
         }
+        if let Some(new_latency) = new_latency {
+            match self.audio.stream.set_latency(new_latency) {
+                Ok(audio_tx) => {
+                    let _ = self
+                        .emulator_tx
+                        .send(EmulatorCommand::SetAudioSender(audio_tx));
+                }
+                Err(e) => {
+                    log::error!("Failed to change audio latency: {:?}", e);
+                }
+            }
+        }
     }
 
     fn name(&self) -> Option<&str> {
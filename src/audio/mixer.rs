@@ -0,0 +1,197 @@
+use serde::{Deserialize, Serialize};
+
+use crate::emulation::SAMPLE_RATE;
+
+pub const CHANNEL_COUNT: usize = 6;
+
+/// The output level of every NES audio channel at one point in time
+pub type ChannelSample = [f32; CHANNEL_COUNT];
+pub type StereoSample = [f32; 2];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AudioChannel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+    /// Expansion audio from the cartridge (VRC6, MMC5, N163 etc.)
+    Mapper,
+}
+
+impl AudioChannel {
+    pub const ALL: [AudioChannel; CHANNEL_COUNT] = [
+        AudioChannel::Pulse1,
+        AudioChannel::Pulse2,
+        AudioChannel::Triangle,
+        AudioChannel::Noise,
+        AudioChannel::Dmc,
+        AudioChannel::Mapper,
+    ];
+}
+
+impl std::fmt::Display for AudioChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AudioChannel::Pulse1 => write!(f, "Pulse 1"),
+            AudioChannel::Pulse2 => write!(f, "Pulse 2"),
+            AudioChannel::Triangle => write!(f, "Triangle"),
+            AudioChannel::Noise => write!(f, "Noise"),
+            AudioChannel::Dmc => write!(f, "DMC"),
+            AudioChannel::Mapper => write!(f, "Expansion"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub struct ChannelSettings {
+    /// In %
+    pub volume: u8,
+    /// -100 is hard left, 0 is center and 100 is hard right
+    pub pan: i8,
+    #[serde(default)]
+    pub muted: bool,
+}
+
+impl Default for ChannelSettings {
+    fn default() -> Self {
+        Self {
+            volume: 100,
+            pan: 0,
+            muted: false,
+        }
+    }
+}
+
+impl ChannelSettings {
+    fn gains(&self) -> StereoSample {
+        if self.muted {
+            return [0.0; 2];
+        }
+        let volume = self.volume as f32 / 100.0;
+        let pan = self.pan.clamp(-100, 100) as f32 / 100.0;
+        [volume * (1.0 - pan).min(1.0), volume * (1.0 + pan).min(1.0)]
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub struct MixerSettings {
+    pub pulse1: ChannelSettings,
+    pub pulse2: ChannelSettings,
+    pub triangle: ChannelSettings,
+    pub noise: ChannelSettings,
+    pub dmc: ChannelSettings,
+    pub mapper: ChannelSettings,
+}
+
+impl MixerSettings {
+    pub fn lookup(&mut self, channel: &AudioChannel) -> &mut ChannelSettings {
+        match channel {
+            AudioChannel::Pulse1 => &mut self.pulse1,
+            AudioChannel::Pulse2 => &mut self.pulse2,
+            AudioChannel::Triangle => &mut self.triangle,
+            AudioChannel::Noise => &mut self.noise,
+            AudioChannel::Dmc => &mut self.dmc,
+            AudioChannel::Mapper => &mut self.mapper,
+        }
+    }
+}
+
+/// One pole high pass filter, removes the DC offset of the NES output
+#[derive(Clone)]
+struct HighPass {
+    alpha: f32,
+    prev_input: f32,
+    prev_output: f32,
+}
+
+impl HighPass {
+    fn new(cutoff: f32, sample_rate: f32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        Self {
+            alpha: rc / (rc + 1.0 / sample_rate),
+            prev_input: 0.0,
+            prev_output: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        self.prev_output = self.alpha * (self.prev_output + input - self.prev_input);
+        self.prev_input = input;
+        self.prev_output
+    }
+}
+
+/// Mixes the separate NES channels down to stereo using the non-linear mixing of the NES APU
+/// (see https://www.nesdev.org/wiki/APU_Mixer), with a separate volume and pan for each channel.
+/// The channels are mixed at their original levels so the curve stays the same as on the NES,
+/// and each channel's share of the mix is then scaled by its volume. That way turning one channel
+/// down doesn't change how loud the others sound.
+pub struct Mixer {
+    gains: [StereoSample; CHANNEL_COUNT],
+    high_pass: [HighPass; 2],
+}
+
+impl Mixer {
+    pub fn new(settings: &MixerSettings) -> Self {
+        let high_pass = HighPass::new(37.0, SAMPLE_RATE);
+        let mut mixer = Self {
+            gains: [[1.0; 2]; CHANNEL_COUNT],
+            high_pass: [high_pass.clone(), high_pass],
+        };
+        mixer.set_settings(settings);
+        mixer
+    }
+
+    pub fn set_settings(&mut self, settings: &MixerSettings) {
+        let mut settings = settings.clone();
+        for (idx, channel) in AudioChannel::ALL.iter().enumerate() {
+            self.gains[idx] = settings.lookup(channel).gains();
+        }
+    }
+
+    pub fn process(&mut self, input: &[ChannelSample], output: &mut Vec<StereoSample>) {
+        for channels in input {
+            let [pulse1, pulse2, triangle, noise, dmc, mapper] = *channels;
+
+            let pulse = pulse1 + pulse2;
+            let pulse_out = if pulse > 0.0 {
+                95.88 / (8128.0 / pulse + 100.0)
+            } else {
+                0.0
+            };
+            let tnd_weights = [triangle / 8227.0, noise / 12241.0, dmc / 22638.0];
+            let tnd: f32 = tnd_weights.iter().sum();
+            let tnd_out = if tnd > 0.0 {
+                159.79 / (1.0 / tnd + 100.0)
+            } else {
+                0.0
+            };
+
+            let mut stereo_sample = [0.0; 2];
+            for (side, out) in stereo_sample.iter_mut().enumerate() {
+                let gain = |channel: AudioChannel| self.gains[channel as usize][side];
+                let pulse_share = if pulse > 0.0 {
+                    (pulse1 * gain(AudioChannel::Pulse1) + pulse2 * gain(AudioChannel::Pulse2))
+                        / pulse
+                } else {
+                    0.0
+                };
+                let tnd_share = if tnd > 0.0 {
+                    (tnd_weights[0] * gain(AudioChannel::Triangle)
+                        + tnd_weights[1] * gain(AudioChannel::Noise)
+                        + tnd_weights[2] * gain(AudioChannel::Dmc))
+                        / tnd
+                } else {
+                    0.0
+                };
+                *out = self.high_pass[side].process(
+                    pulse_out * pulse_share
+                        + tnd_out * tnd_share
+                        + mapper * gain(AudioChannel::Mapper),
+                );
+            }
+            output.push(stereo_sample);
+        }
+    }
+}
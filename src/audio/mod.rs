@@ -1,59 +1,222 @@
-use std::ops::Add;
+use std::path::PathBuf;
 
-use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SendError, SyncSender, TryRecvError, TrySendError};
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired, AudioStatus};
 use sdl2::{AudioSubsystem, Sdl};
 use serde::{Deserialize, Serialize};
 
-use crate::settings::Settings;
+use crate::{bundle::Bundle, emulation::pacing::FramePacing, settings::Settings};
+
+use self::{
+    mixer::{MixerSettings, StereoSample},
+    sink::{FileSink, NullSink},
+};
 
 pub mod gui;
+pub mod mixer;
+pub mod rate_control;
+pub mod resampler;
+pub mod sink;
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash)]
 pub struct AudioSettings {
     pub volume: u8,
     #[serde(default = "AudioSettings::default_latency")]
     pub latency: u8,
+    #[serde(default = "AudioSettings::default_sample_rate")]
+    pub sample_rate: u32,
     pub output_device: Option<String>,
+    pub mixer: Option<MixerSettings>,
 }
 impl AudioSettings {
     fn default_latency() -> u8 {
         30
     }
+    fn default_sample_rate() -> u32 {
+        48_000
+    }
+    pub fn get_mixer(&mut self) -> &MixerSettings {
+        self.mixer.get_or_insert_with(|| {
+            Bundle::current()
+                .config
+                .default_settings
+                .audio
+                .mixer
+                .clone()
+                .unwrap_or_default()
+        })
+    }
+}
+static MUTED: AtomicBool = AtomicBool::new(false);
+
+/// Silences the output without touching the volume setting
+pub fn set_muted(muted: bool) {
+    MUTED.store(muted, Ordering::Relaxed);
+}
+
+struct AudioReceiverCallback {
+    rx: AudioReceiver,
+    underruns: Arc<AtomicU64>,
 }
-struct AudioReceiverCallback(AudioReceiver);
 
 impl AudioCallback for AudioReceiverCallback {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        let consumer = &mut self.0;
+        let consumer = &mut self.rx;
 
-        let volume = Settings::current().audio.volume as f32 / 100.0;
+        let volume = if MUTED.load(Ordering::Relaxed) {
+            0.0
+        } else {
+            Settings::current().audio.volume as f32 / 100.0
+        };
         let mut missing_samples = 0;
-        for s in out {
-            if let Ok(new_sample) = consumer.try_recv() {
-                *s = new_sample * volume;
+        for frame in out.chunks_exact_mut(2) {
+            if let Ok([left, right]) = consumer.try_recv() {
+                frame[0] = left * volume;
+                frame[1] = right * volume;
             } else {
                 missing_samples += 1;
-                *s = 0.0;
+                frame.fill(0.0);
             }
         }
         if missing_samples > 0 {
             log::trace!("Buffer underrun: {missing_samples} samples");
+            self.underruns.fetch_add(1, Ordering::Relaxed);
         }
     }
 }
-pub type AudioSender = SyncSender<f32>;
-pub type AudioReceiver = Receiver<f32>;
+
+#[derive(Clone)]
+pub struct AudioSender {
+    tx: SyncSender<StereoSample>,
+    buffered: Arc<AtomicUsize>,
+    capacity: usize,
+    sample_rate: Arc<AtomicU32>,
+}
+
+impl AudioSender {
+    /// Blocks until there is room in the buffer
+    pub fn send(&self, sample: StereoSample) -> Result<(), SendError<StereoSample>> {
+        self.buffered.fetch_add(1, Ordering::Relaxed);
+        self.tx.send(sample).inspect_err(|_| {
+            self.buffered.fetch_sub(1, Ordering::Relaxed);
+        })
+    }
+
+    pub fn try_send(&self, sample: StereoSample) -> Result<(), TrySendError<StereoSample>> {
+        self.buffered.fetch_add(1, Ordering::Relaxed);
+        self.tx.try_send(sample).inspect_err(|_| {
+            self.buffered.fetch_sub(1, Ordering::Relaxed);
+        })
+    }
+
+    /// How full the audio buffer is, from 0.0 (empty) to 1.0 (full)
+    pub fn fill_level(&self) -> f32 {
+        self.buffered.load(Ordering::Relaxed) as f32 / self.capacity as f32
+    }
+
+    /// The sample rate of the currently opened output device
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate.load(Ordering::Relaxed)
+    }
+}
+
+pub struct AudioReceiver {
+    rx: Receiver<StereoSample>,
+    buffered: Arc<AtomicUsize>,
+    sample_rate: Arc<AtomicU32>,
+}
+
+impl AudioReceiver {
+    fn try_recv(&self) -> Result<StereoSample, TryRecvError> {
+        let sample = self.rx.try_recv()?;
+        self.buffered.fetch_sub(1, Ordering::Relaxed);
+        Ok(sample)
+    }
+
+    fn set_sample_rate(&self, sample_rate: u32) {
+        self.sample_rate.store(sample_rate, Ordering::Relaxed);
+    }
+}
+
+fn audio_channel(capacity: usize, sample_rate: u32) -> (AudioSender, AudioReceiver) {
+    let (tx, rx) = sync_channel(capacity);
+    let buffered = Arc::new(AtomicUsize::new(0));
+    let sample_rate = Arc::new(AtomicU32::new(sample_rate));
+    (
+        AudioSender {
+            tx,
+            buffered: buffered.clone(),
+            capacity,
+            sample_rate: sample_rate.clone(),
+        },
+        AudioReceiver {
+            rx,
+            buffered,
+            sample_rate,
+        },
+    )
+}
+
+/// Somewhere for the emulator audio to go, f.ex an audio device
+pub trait AudioOutput {
+    /// Starts consuming audio, returns the sender the emulator should push samples to
+    fn start(&mut self) -> Result<AudioSender>;
+
+    /// Rebuilds the audio buffer with a new latency.
+    /// Returns the sender of the new buffer, it replaces the one the emulator is currently using.
+    fn set_latency(&mut self, latency: Duration) -> Result<AudioSender>;
+
+    /// The number of times the output has run out of samples since it was (re)built
+    fn underruns(&self) -> u64;
+
+    fn set_output_device(&mut self, _output_device_name: Option<String>) {}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioBackend {
+    Sdl,
+    /// Consume the audio without playing it
+    Null,
+    /// Write the audio to a WAV file
+    File(PathBuf),
+}
+
+impl AudioBackend {
+    /// Picks the backend from the `--audio-output=<sdl|null|file:path>` argument, defaults to SDL
+    pub fn from_args() -> Self {
+        std::env::args()
+            .find_map(|arg| {
+                arg.strip_prefix("--audio-output=")
+                    .map(|backend| match backend {
+                        "null" => Some(AudioBackend::Null),
+                        "sdl" => Some(AudioBackend::Sdl),
+                        backend => backend
+                            .strip_prefix("file:")
+                            .map(|path| AudioBackend::File(PathBuf::from(path))),
+                    })
+                    .map(|backend| {
+                        backend.unwrap_or_else(|| {
+                            log::warn!("Unknown audio output '{arg}', using SDL");
+                            AudioBackend::Sdl
+                        })
+                    })
+            })
+            .unwrap_or(AudioBackend::Sdl)
+    }
+}
 
 pub struct Stream {
     tx: Option<AudioSender>,
     output_device_name: Option<String>,
     audio_device: Option<AudioDevice<AudioReceiverCallback>>,
+    underruns: Arc<AtomicU64>,
 }
 
 impl Stream {
@@ -65,14 +228,8 @@ impl Stream {
         log::debug!(
             "Trying to start audio: sample rate={desired_sample_rate}, latency={latency:?}"
         );
-        let sample_latency =
-            (latency.as_secs_f32() * desired_sample_rate as f32 * 1.0).ceil() as u16;
-
-        let (tx, audio_rx) = sync_channel(sample_latency as usize);
-        // Fill with silence
-        for _ in 0..sample_latency {
-            let _ = tx.send(0.0);
-        }
+        let (tx, audio_rx) = Stream::new_channel(latency, desired_sample_rate);
+        let underruns = Arc::new(AtomicU64::new(0));
 
         let output_device = &Settings::current().audio.output_device;
         let audio_device = Stream::new_audio_device(
@@ -80,19 +237,31 @@ impl Stream {
             audio_subsystem,
             output_device,
             audio_rx,
+            underruns.clone(),
         )?;
         Ok(Self {
             tx: Some(tx),
             output_device_name: output_device.clone(),
             audio_device: Some(audio_device),
+            underruns,
         })
     }
 
-    pub fn start(&mut self) -> Result<AudioSender> {
-        if let Some(device) = &self.audio_device {
-            device.resume();
+    /// Creates a channel that holds `latency` worth of samples, filled with silence
+    fn new_channel(latency: Duration, sample_rate: u32) -> (AudioSender, AudioReceiver) {
+        let latency = match Bundle::current().config.frame_pacing {
+            FramePacing::Audio => latency,
+            // Leave room above the target fill level for the dynamic rate control to work with
+            FramePacing::Vsync => latency * 2,
+        };
+        let sample_latency = (latency.as_secs_f32() * sample_rate as f32).ceil() as usize;
+
+        let (tx, audio_rx) = audio_channel(sample_latency, sample_rate);
+        // Fill with silence
+        for _ in 0..sample_latency {
+            let _ = tx.send([0.0; 2]);
         }
-        self.tx.take().ok_or(anyhow!("Stream already started"))
+        (tx, audio_rx)
     }
 
     fn new_audio_device(
@@ -100,8 +269,9 @@ impl Stream {
         audio_subsystem: &AudioSubsystem,
         output_device: &Option<String>,
         audio_rx: AudioReceiver,
+        underruns: Arc<AtomicU64>,
     ) -> Result<AudioDevice<AudioReceiverCallback>> {
-        let channels = 1;
+        let channels = 2;
 
         let desired_spec = AudioSpecDesired {
             freq: Some(desired_sample_rate as i32),
@@ -120,15 +290,61 @@ impl Stream {
             .or_else(|| Audio::get_default_device_name_for_subsystem(audio_subsystem));
 
         let output_device = audio_subsystem
-            .open_playback(output_device.as_deref(), &desired_spec, |_| {
-                AudioReceiverCallback(audio_rx)
+            .open_playback(output_device.as_deref(), &desired_spec, |obtained_spec| {
+                // Let the sending side know what rate to resample to
+                audio_rx.set_sample_rate(obtained_spec.freq as u32);
+                AudioReceiverCallback {
+                    rx: audio_rx,
+                    underruns,
+                }
             })
             .map_err(anyhow::Error::msg)?;
         log::info!("Audio started with {:?}", output_device.spec());
         Ok(output_device)
     }
+}
+
+impl AudioOutput for Stream {
+    fn start(&mut self) -> Result<AudioSender> {
+        if let Some(device) = &self.audio_device {
+            device.resume();
+        }
+        self.tx.take().ok_or(anyhow!("Stream already started"))
+    }
 
-    pub(crate) fn set_output_device(&mut self, output_device_name: Option<String>) {
+    fn set_latency(&mut self, latency: Duration) -> Result<AudioSender> {
+        let audio_device = self
+            .audio_device
+            .take()
+            .ok_or(anyhow!("No audio device to rebuild"))?;
+        let subsystem = audio_device.subsystem().clone();
+        let old_device_status = audio_device.status();
+        let desired_sample_rate = audio_device.spec().freq as u32;
+        // Closing the old device drops the old receiver, which unblocks the emulator if it's waiting to send
+        drop(audio_device);
+
+        log::debug!("Rebuilding audio stream with latency={latency:?}");
+        let (tx, audio_rx) = Stream::new_channel(latency, desired_sample_rate);
+        self.underruns.store(0, Ordering::Relaxed);
+        let audio_device = Stream::new_audio_device(
+            desired_sample_rate,
+            &subsystem,
+            &self.output_device_name,
+            audio_rx,
+            self.underruns.clone(),
+        )?;
+        if old_device_status == AudioStatus::Playing {
+            audio_device.resume();
+        }
+        self.audio_device = Some(audio_device);
+        Ok(tx)
+    }
+
+    fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    fn set_output_device(&mut self, output_device_name: Option<String>) {
         if self.output_device_name != output_device_name {
             if let Some(audio_device) = self.audio_device.take() {
                 let subsystem = audio_device.subsystem().clone();
@@ -140,7 +356,8 @@ impl Stream {
                     desired_sample_rate,
                     &subsystem,
                     &output_device_name,
-                    old_callback.0,
+                    old_callback.rx,
+                    old_callback.underruns,
                 ) {
                     Ok(audio_device) => {
                         if old_device_status == AudioStatus::Playing {
@@ -158,21 +375,70 @@ impl Stream {
     }
 }
 
+#[derive(Clone, Debug)]
+pub enum AudioEvent {
+    OutputDeviceAdded,
+    OutputDeviceRemoved,
+}
+
+pub trait ToAudioEvent {
+    /// Convert the struct to an AudioEvent
+    fn to_audio_event(&self) -> Option<AudioEvent>;
+}
+
+impl ToAudioEvent for sdl2::event::Event {
+    fn to_audio_event(&self) -> Option<AudioEvent> {
+        match self {
+            sdl2::event::Event::AudioDeviceAdded {
+                iscapture: false, ..
+            } => Some(AudioEvent::OutputDeviceAdded),
+            sdl2::event::Event::AudioDeviceRemoved {
+                iscapture: false, ..
+            } => Some(AudioEvent::OutputDeviceRemoved),
+            _ => None,
+        }
+    }
+}
+
 pub struct Audio {
-    pub stream: Stream,
-    available_device_names: Vec<String>,
-    next_device_names_clear: Instant,
-    audio_subsystem: AudioSubsystem,
+    pub stream: Box<dyn AudioOutput>,
+    audio_subsystem: Option<AudioSubsystem>,
 }
 
 impl Audio {
-    pub fn new(sdl_context: &Sdl, latency: Duration, desired_sample_rate: u32) -> Result<Self> {
-        let audio_subsystem = sdl_context.audio().map_err(anyhow::Error::msg)?;
+    pub fn new(
+        sdl_context: &Sdl,
+        latency: Duration,
+        desired_sample_rate: u32,
+        backend: AudioBackend,
+    ) -> Result<Self> {
+        let (stream, audio_subsystem): (Box<dyn AudioOutput>, _) = match backend {
+            AudioBackend::Sdl => {
+                match sdl_context
+                    .audio()
+                    .map_err(anyhow::Error::msg)
+                    .and_then(|audio_subsystem| {
+                        Ok((
+                            Stream::new(&audio_subsystem, latency, desired_sample_rate)?,
+                            audio_subsystem,
+                        ))
+                    }) {
+                    Ok((stream, audio_subsystem)) => (Box::new(stream), Some(audio_subsystem)),
+                    Err(e) => {
+                        log::warn!("Could not start audio, continuing without it: {:?}", e);
+                        (Box::new(NullSink::new(latency, desired_sample_rate)), None)
+                    }
+                }
+            }
+            AudioBackend::Null => (Box::new(NullSink::new(latency, desired_sample_rate)), None),
+            AudioBackend::File(path) => (
+                Box::new(FileSink::new(path, latency, desired_sample_rate)),
+                None,
+            ),
+        };
 
         Ok(Audio {
-            stream: Stream::new(&audio_subsystem, latency, desired_sample_rate)?,
-            available_device_names: vec![],
-            next_device_names_clear: Instant::now(),
+            stream,
             audio_subsystem,
         })
     }
@@ -184,10 +450,14 @@ impl Audio {
     }
 
     pub fn get_default_device_name(&self) -> Option<String> {
-        let subsystem = &self.audio_subsystem;
-        Self::get_available_output_device_names_for_subsystem(subsystem)
-            .first()
-            .cloned()
+        self.get_available_output_device_names().first().cloned()
+    }
+
+    pub fn get_available_output_device_names(&self) -> Vec<String> {
+        self.audio_subsystem
+            .as_ref()
+            .map(Self::get_available_output_device_names_for_subsystem)
+            .unwrap_or_default()
     }
 
     pub fn get_available_output_device_names_for_subsystem(
@@ -202,23 +472,19 @@ impl Audio {
         }
     }
 
-    pub fn sync_audio_devices(&mut self) {
-        let available_device_names =
-            Self::get_available_output_device_names_for_subsystem(&self.audio_subsystem);
-        if self.next_device_names_clear < Instant::now() {
-            self.next_device_names_clear = Instant::now().add(Duration::new(1, 0));
-            self.available_device_names
-                .clone_from(&available_device_names);
-        }
-
-        let selected_device = &mut Settings::current_mut().audio.output_device;
-        if let Some(name) = selected_device {
-            if !available_device_names.contains(name) {
-                *selected_device = None;
-            }
-        }
-        if selected_device.is_none() {
-            *selected_device = self.get_default_device_name();
+    /// Moves the stream to the preferred output device (from the settings) if it's available, otherwise to the default device.
+    /// Called whenever an output device is added or removed.
+    pub fn sync_output_device(&mut self) {
+        if self.audio_subsystem.is_none() {
+            return;
         }
+        let available_device_names = self.get_available_output_device_names();
+        let output_device = Settings::current()
+            .audio
+            .output_device
+            .clone()
+            .filter(|name| available_device_names.contains(name))
+            .or_else(|| available_device_names.first().cloned());
+        self.stream.set_output_device(output_device);
     }
 }
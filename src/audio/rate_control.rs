@@ -0,0 +1,16 @@
+/// Dynamic rate control as described in https://github.com/libretro/docs/blob/master/archive/ratecontrol.pdf
+/// The resampling ratio is nudged (by at most `max_delta`) depending on how full the audio buffer is,
+/// so that the buffer hovers around half full instead of slowly drifting into an under- or overrun.
+pub struct RateControl {
+    max_delta: f32,
+}
+
+impl RateControl {
+    pub fn new(max_delta: f32) -> Self {
+        Self { max_delta }
+    }
+
+    pub fn ratio(&self, fill_level: f32) -> f32 {
+        1.0 + (1.0 - 2.0 * fill_level.clamp(0.0, 1.0)) * self.max_delta
+    }
+}
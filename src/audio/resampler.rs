@@ -0,0 +1,56 @@
+use super::mixer::StereoSample;
+
+/// Resamples a stream of stereo audio using cubic (Catmull-Rom) interpolation.
+/// The ratio can be changed between calls to `process` without any discontinuities in the output,
+/// which makes it suitable for both fine speed adjustments and converting to arbitrary output rates.
+pub struct Resampler {
+    ratio: f32,
+    position: f32,
+    history: [StereoSample; 4],
+}
+
+impl Resampler {
+    pub fn new() -> Self {
+        Self {
+            ratio: 1.0,
+            position: 0.0,
+            history: [[0.0; 2]; 4],
+        }
+    }
+
+    /// The number of output samples per input sample
+    pub fn set_ratio(&mut self, ratio: f32) {
+        self.ratio = ratio.max(f32::EPSILON);
+    }
+
+    pub fn process(&mut self, input: &[StereoSample], output: &mut Vec<StereoSample>) {
+        let step = 1.0 / self.ratio;
+        for &sample in input {
+            self.history.rotate_left(1);
+            self.history[3] = sample;
+
+            // Interpolate between history[1] and history[2]
+            while self.position < 1.0 {
+                let [y0, y1, y2, y3] = self.history;
+                output.push(std::array::from_fn(|side| {
+                    catmull_rom(&[y0[side], y1[side], y2[side], y3[side]], self.position)
+                }));
+                self.position += step;
+            }
+            self.position -= 1.0;
+        }
+    }
+}
+
+impl Default for Resampler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn catmull_rom(&[y0, y1, y2, y3]: &[f32; 4], t: f32) -> f32 {
+    let a = -0.5 * y0 + 1.5 * y1 - 1.5 * y2 + 0.5 * y3;
+    let b = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+    let c = -0.5 * y0 + 0.5 * y2;
+    ((a * t + b) * t + c) * t + y1
+}
@@ -0,0 +1,383 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use super::{mixer::StereoSample, AudioOutput, AudioReceiver, AudioSender, Stream};
+
+/// How often the consumer thread wakes up to pull samples
+const CONSUME_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Where the consumed samples end up
+trait SampleSink: Send + 'static {
+    fn write(&mut self, sample: StereoSample);
+}
+
+/// Throws the samples away
+struct Discard;
+
+impl SampleSink for Discard {
+    fn write(&mut self, _sample: StereoSample) {}
+}
+
+/// Keeps track of how many samples a real device would have consumed by now
+struct Pacer {
+    sample_rate: f64,
+    consumed: u64,
+}
+
+impl Pacer {
+    fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate: sample_rate as f64,
+            consumed: 0,
+        }
+    }
+
+    /// Hands every sample that is due `elapsed` after the start to `sink`, with silence where the
+    /// buffer ran dry. Returns the number of missing samples.
+    fn consume(
+        &mut self,
+        rx: &AudioReceiver,
+        elapsed: Duration,
+        sink: &mut impl SampleSink,
+    ) -> u64 {
+        let due = (elapsed.as_secs_f64() * self.sample_rate) as u64;
+        let mut missing_samples = 0;
+        while self.consumed < due {
+            sink.write(rx.try_recv().unwrap_or_else(|_| {
+                missing_samples += 1;
+                [0.0; 2]
+            }));
+            self.consumed += 1;
+        }
+        missing_samples
+    }
+}
+
+/// Pulls samples from the audio buffer at the rate a real device would, and hands them to a `SampleSink`.
+struct RealtimeConsumer<S: SampleSink> {
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<S>>,
+}
+
+impl<S: SampleSink> RealtimeConsumer<S> {
+    fn spawn(rx: AudioReceiver, underruns: Arc<AtomicU64>, mut sink: S) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let handle = std::thread::spawn({
+            let running = running.clone();
+            move || {
+                let mut pacer = Pacer::new(rx.sample_rate.load(Ordering::Relaxed));
+                let start = Instant::now();
+                while running.load(Ordering::Relaxed) {
+                    std::thread::sleep(CONSUME_INTERVAL);
+                    let missing_samples = pacer.consume(&rx, start.elapsed(), &mut sink);
+                    if missing_samples > 0 {
+                        log::trace!("Buffer underrun: {missing_samples} samples");
+                        underruns.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                sink
+            }
+        });
+        Self {
+            running,
+            handle: Some(handle),
+        }
+    }
+
+    /// Stops consuming and gives back the sink
+    fn stop(mut self) -> Result<S> {
+        self.running.store(false, Ordering::Relaxed);
+        self.handle
+            .take()
+            .ok_or(anyhow!("Consumer already stopped"))?
+            .join()
+            .map_err(|_| anyhow!("The audio consumer thread panicked"))
+    }
+}
+
+impl<S: SampleSink> Drop for RealtimeConsumer<S> {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Consumes the samples the same way an audio device would, but discards them.
+/// Used when there is no audio device, and to run the emulator without sound.
+pub struct NullSink {
+    sample_rate: u32,
+    tx: Option<AudioSender>,
+    rx: Option<AudioReceiver>,
+    consumer: Option<RealtimeConsumer<Discard>>,
+    underruns: Arc<AtomicU64>,
+}
+
+impl NullSink {
+    pub fn new(latency: Duration, sample_rate: u32) -> Self {
+        log::info!("Audio is disabled, using a null sink at {sample_rate}Hz");
+        let (tx, rx) = Stream::new_channel(latency, sample_rate);
+        Self {
+            sample_rate,
+            tx: Some(tx),
+            rx: Some(rx),
+            consumer: None,
+            underruns: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl AudioOutput for NullSink {
+    fn start(&mut self) -> Result<AudioSender> {
+        let rx = self.rx.take().ok_or(anyhow!("Sink already started"))?;
+        self.consumer = Some(RealtimeConsumer::spawn(rx, self.underruns.clone(), Discard));
+        self.tx.take().ok_or(anyhow!("Sink already started"))
+    }
+
+    fn set_latency(&mut self, latency: Duration) -> Result<AudioSender> {
+        self.consumer = None;
+        let (tx, rx) = Stream::new_channel(latency, self.sample_rate);
+        self.underruns.store(0, Ordering::Relaxed);
+        self.consumer = Some(RealtimeConsumer::spawn(rx, self.underruns.clone(), Discard));
+        Ok(tx)
+    }
+
+    fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
+    }
+}
+
+/// Writes a 32 bit float stereo WAV file and keeps the size fields of the header up to date
+struct WavWriter<W: Write + Seek> {
+    writer: W,
+    data_len: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    fn create(path: &Path, sample_rate: u32) -> Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    const HEADER_LEN: u32 = 44;
+    /// The size fields of the header are 32 bits, which limits a WAV file to 4 GiB
+    const MAX_DATA_LEN: u32 = u32::MAX - (Self::HEADER_LEN - 8);
+
+    fn new(mut writer: W, sample_rate: u32) -> Result<Self> {
+        let channels: u16 = 2;
+        let bits_per_sample: u16 = 32;
+        let block_align = channels * bits_per_sample / 8;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(Self::HEADER_LEN - 8).to_le_bytes())?;
+        writer.write_all(b"WAVEfmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&3u16.to_le_bytes())?; // IEEE float
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&bits_per_sample.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        Ok(Self {
+            writer,
+            data_len: 0,
+        })
+    }
+
+    fn write(&mut self, sample: StereoSample) -> Result<()> {
+        let Some(data_len) = self
+            .data_len
+            .checked_add(8)
+            .filter(|data_len| *data_len <= Self::MAX_DATA_LEN)
+        else {
+            bail!("The audio file has reached the maximum size of a WAV file");
+        };
+        for s in sample {
+            self.writer.write_all(&s.to_le_bytes())?;
+        }
+        self.data_len = data_len;
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<()> {
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer
+            .write_all(&(Self::HEADER_LEN - 8 + self.data_len).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_all(&self.data_len.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        if let Err(e) = self.finalize() {
+            log::error!("Failed to finalize the audio file: {:?}", e);
+        }
+    }
+}
+
+/// The file being recorded, or `None` once writing to it has failed
+type Recording = Option<WavWriter<BufWriter<File>>>;
+
+impl SampleSink for Recording {
+    fn write(&mut self, sample: StereoSample) {
+        if let Some(wav_writer) = self {
+            if let Err(e) = wav_writer.write(sample) {
+                log::error!("Failed to write audio to file: {:?}", e);
+                // Dropping the writer finalizes the file with what was written so far
+                *self = None;
+            }
+        }
+    }
+}
+
+/// Consumes the samples in real time like `NullSink`, but writes them to a WAV file.
+pub struct FileSink {
+    path: PathBuf,
+    sample_rate: u32,
+    tx: Option<AudioSender>,
+    rx: Option<AudioReceiver>,
+    consumer: Option<RealtimeConsumer<Recording>>,
+    underruns: Arc<AtomicU64>,
+}
+
+impl FileSink {
+    pub fn new(path: PathBuf, latency: Duration, sample_rate: u32) -> Self {
+        log::info!("Writing audio to {path:?} at {sample_rate}Hz");
+        let (tx, rx) = Stream::new_channel(latency, sample_rate);
+        Self {
+            path,
+            sample_rate,
+            tx: Some(tx),
+            rx: Some(rx),
+            consumer: None,
+            underruns: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl AudioOutput for FileSink {
+    fn start(&mut self) -> Result<AudioSender> {
+        let rx = self.rx.take().ok_or(anyhow!("Sink already started"))?;
+        let recording = Some(WavWriter::create(&self.path, self.sample_rate)?);
+        self.consumer = Some(RealtimeConsumer::spawn(
+            rx,
+            self.underruns.clone(),
+            recording,
+        ));
+        self.tx.take().ok_or(anyhow!("Sink already started"))
+    }
+
+    fn set_latency(&mut self, latency: Duration) -> Result<AudioSender> {
+        // Keep writing to the same file, only the buffer in front of it changes
+        let recording = match self.consumer.take() {
+            Some(consumer) => consumer.stop()?,
+            None => Some(WavWriter::create(&self.path, self.sample_rate)?),
+        };
+        let (tx, rx) = Stream::new_channel(latency, self.sample_rate);
+        self.underruns.store(0, Ordering::Relaxed);
+        self.consumer = Some(RealtimeConsumer::spawn(
+            rx,
+            self.underruns.clone(),
+            recording,
+        ));
+        Ok(tx)
+    }
+
+    fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::audio::audio_channel;
+
+    struct Collect(Vec<StereoSample>);
+
+    impl SampleSink for Collect {
+        fn write(&mut self, sample: StereoSample) {
+            self.0.push(sample);
+        }
+    }
+
+    #[test]
+    fn pacer_consumes_at_the_sample_rate() {
+        let (tx, rx) = audio_channel(100, 1_000);
+        for _ in 0..20 {
+            tx.try_send([0.5, -0.5]).unwrap();
+        }
+        let mut pacer = Pacer::new(1_000);
+        let mut sink = Collect(Vec::new());
+
+        assert_eq!(pacer.consume(&rx, Duration::from_millis(5), &mut sink), 0);
+        assert_eq!(sink.0.len(), 5);
+        assert_eq!(tx.fill_level(), 0.15);
+
+        assert_eq!(pacer.consume(&rx, Duration::from_millis(5), &mut sink), 0);
+        assert_eq!(sink.0.len(), 5);
+
+        assert_eq!(pacer.consume(&rx, Duration::from_millis(30), &mut sink), 10);
+        assert_eq!(sink.0.len(), 30);
+        assert_eq!(sink.0[19], [0.5, -0.5]);
+        assert_eq!(sink.0[20], [0.0; 2]);
+    }
+
+    #[test]
+    fn wav_writer_writes_a_valid_wav_file() -> Result<()> {
+        let mut wav = Cursor::new(Vec::new());
+        {
+            let mut wav_writer = WavWriter::new(&mut wav, 48_000)?;
+            for _ in 0..100 {
+                wav_writer.write([0.5, -0.5])?;
+            }
+        }
+
+        let wav = wav.into_inner();
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(wav[offset..offset + 4].try_into().unwrap());
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(24), 48_000);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32_at(4) as usize, wav.len() - 8);
+        assert_eq!(u32_at(40), 100 * 8);
+        assert_eq!(
+            wav.len(),
+            WavWriter::<Cursor<Vec<u8>>>::HEADER_LEN as usize + 100 * 8
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn wav_writer_stops_at_the_size_limit() -> Result<()> {
+        let mut wav_writer = WavWriter::new(Cursor::new(Vec::new()), 48_000)?;
+        let max_data_len = WavWriter::<Cursor<Vec<u8>>>::MAX_DATA_LEN;
+        wav_writer.data_len = max_data_len - 8;
+        assert!(wav_writer.write([0.0; 2]).is_ok());
+        assert!(wav_writer.write([0.0; 2]).is_err());
+        assert_eq!(wav_writer.data_len, max_data_len);
+        Ok(())
+    }
+}
@@ -8,7 +8,11 @@ use anyhow::Result;
 use directories::ProjectDirs;
 use serde::Deserialize;
 
-use crate::{emulation::NesRegion, input::gui::InputButtonsVoca, settings::Settings};
+use crate::{
+    emulation::{pacing::FramePacing, NesRegion},
+    input::gui::InputButtonsVoca,
+    settings::Settings,
+};
 
 #[derive(Deserialize, Default, Debug)]
 pub struct Vocabulary {
@@ -29,6 +33,8 @@ pub struct BuildConfiguration {
     #[serde(default = "Default::default")]
     pub enable_vsync: bool,
     #[serde(default = "Default::default")]
+    pub frame_pacing: FramePacing,
+    #[serde(default = "Default::default")]
     pub start_in_fullscreen: bool,
     #[serde(default = "Default::default")]
     pub vocabulary: Vocabulary,
@@ -59,6 +65,8 @@ pub struct Bundle {
     pub settings_path: PathBuf,
     pub config: BuildConfiguration,
     pub rom: Vec<u8>,
+    /// SDL game controller mappings (https://github.com/mdqinc/SDL_GameControllerDB)
+    pub gamecontrollerdb: String,
     #[cfg(feature = "netplay")]
     pub netplay_rom: Vec<u8>,
 }
@@ -84,6 +92,10 @@ impl Bundle {
 
         let rom = external_rom.unwrap_or(include_bytes!("../config/rom.nes").to_vec());
 
+        let gamecontrollerdb = fs::read_to_string(Path::new("gamecontrollerdb.txt"))
+            .inspect_err(|e| log::info!("Not using external gamecontrollerdb.txt: {:?}", e))
+            .unwrap_or(include_str!("../config/gamecontrollerdb.txt").to_string());
+
         let settings_path = config
             .get_config_dir()
             .unwrap_or(Path::new("").to_path_buf());
@@ -94,6 +106,7 @@ impl Bundle {
             settings_path,
             config,
             rom,
+            gamecontrollerdb,
 
             #[cfg(feature = "netplay")]
             netplay_rom: fs::read(Path::new("netplay-rom.nes"))
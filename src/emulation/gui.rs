@@ -79,6 +79,12 @@ impl GuiComponent for EmulatorGui {
         self.netplay_gui.ui(ui, &mut self.nes_state.lock().unwrap());
     }
 
+    #[cfg(feature = "netplay")]
+    fn overlay(&mut self, ctx: &egui::Context) {
+        self.netplay_gui
+            .overlay(ctx, &self.nes_state.lock().unwrap());
+    }
+
     #[cfg(feature = "netplay")]
     fn messages(&self) -> Option<Vec<String>> {
         self.netplay_gui.messages(&self.nes_state.lock().unwrap())
@@ -1,9 +1,10 @@
 use std::{
     ops::{Deref, DerefMut},
     sync::{
-        mpsc::{channel, Sender},
+        mpsc::{channel, Receiver, Sender},
         Arc, Mutex, RwLock,
     },
+    time::Duration,
 };
 
 use anyhow::Result;
@@ -13,15 +14,26 @@ use serde::{Deserialize, Serialize};
 use thingbuf::{Recycle, ThingBuf};
 
 use crate::{
-    audio::AudioSender,
+    audio::{
+        mixer::{ChannelSample, Mixer, MixerSettings, CHANNEL_COUNT},
+        rate_control::RateControl,
+        resampler::Resampler,
+        AudioSender,
+    },
+    bundle::Bundle,
     fps::RateCounter,
     input::JoypadState,
     settings::{Settings, MAX_PLAYERS},
 };
 
 pub mod gui;
+pub mod pacing;
 pub mod tetanes;
-use self::{gui::EmulatorGui, tetanes::TetanesNesState};
+use self::{
+    gui::EmulatorGui,
+    pacing::{FramePacing, VsyncPacer},
+    tetanes::TetanesNesState,
+};
 pub type LocalNesState = TetanesNesState;
 
 pub const NES_WIDTH: u32 = 256;
@@ -39,9 +51,16 @@ pub type StateHandler = crate::emulation::LocalNesState;
 pub enum EmulatorCommand {
     Reset(bool),
     SetSpeed(f32),
+    SetMixer(MixerSettings),
+    SetAudioSender(AudioSender),
+    SetPaused(bool),
+    SetTurboFrames(u8),
 }
 pub struct Emulator {}
+// The rate the emulator generates audio at, it's resampled to the output device rate before playback
 pub const SAMPLE_RATE: f32 = 44_100.0;
+// The maximum adjustment of the audio resampling ratio when using dynamic rate control
+const MAX_RATE_DELTA: f32 = 0.005;
 
 impl Emulator {
     pub fn new() -> Result<Self> {
@@ -52,7 +71,9 @@ impl Emulator {
         &self,
         audio_tx: AudioSender,
         inputs: Arc<RwLock<[JoypadState; MAX_PLAYERS]>>,
+        applied_inputs: Arc<RwLock<[JoypadState; MAX_PLAYERS]>>,
         frame_buffer: BufferPool,
+        vsync_rx: Receiver<Duration>,
     ) -> Result<(EmulatorGui, Sender<EmulatorCommand>)> {
         #[cfg(not(feature = "netplay"))]
         let nes_state = crate::emulation::LocalNesState::start_rom(
@@ -64,6 +85,8 @@ impl Emulator {
         #[cfg(feature = "netplay")]
         let nes_state = crate::netplay::NetplayStateHandler::new()?;
 
+        let mut nes_state = nes_state;
+        nes_state.set_turbo_frames(*Settings::current_mut().input.get_turbo_frames());
         let nes_state = Arc::new(Mutex::new(nes_state));
         let (command_tx, command_rx) = channel();
 
@@ -71,8 +94,19 @@ impl Emulator {
             let nes_state = nes_state.clone();
 
             move || {
+                let mut audio_tx = audio_tx;
                 let mut audio_buffer = NESAudioFrame::new();
+                let mut mixed_audio = Vec::new();
+                let mut resampled_audio = Vec::new();
+                let mut mixer = Mixer::new(Settings::current_mut().audio.get_mixer());
                 let mut rate_counter = RateCounter::new();
+                let frame_pacing = &Bundle::current().config.frame_pacing;
+                let mut resampler = Resampler::new();
+                let rate_control = RateControl::new(MAX_RATE_DELTA);
+                let mut nes_region = Settings::current_mut().get_nes_region().clone();
+                let mut vsync_pacer = VsyncPacer::new(vsync_rx, &nes_region);
+                let mut speed = 1.0;
+                let mut paused = false;
 
                 loop {
                     #[cfg(feature = "debug")]
@@ -82,41 +116,103 @@ impl Emulator {
                         #[cfg(feature = "debug")]
                         puffin::profile_scope!("push audio");
 
-                        log::trace!("Pushing {:} audio samples", audio_buffer.len());
-                        for s in audio_buffer.iter() {
-                            let _ = audio_tx.send(*s);
+                        let ratio = audio_tx.sample_rate() as f32 / (SAMPLE_RATE * speed);
+                        resampler.set_ratio(
+                            ratio
+                                * match frame_pacing {
+                                    FramePacing::Audio => nes_region.audio_pacing_adjustment(),
+                                    FramePacing::Vsync => rate_control.ratio(audio_tx.fill_level()),
+                                },
+                        );
+                        mixed_audio.clear();
+                        mixer.process(&audio_buffer, &mut mixed_audio);
+                        resampled_audio.clear();
+                        resampler.process(&mixed_audio, &mut resampled_audio);
+
+                        log::trace!("Pushing {:} audio samples", resampled_audio.len());
+                        match frame_pacing {
+                            FramePacing::Audio => {
+                                // Blocks until there is room in the audio buffer, this is what paces the emulation
+                                for s in &resampled_audio {
+                                    let _ = audio_tx.send(*s);
+                                }
+                            }
+                            FramePacing::Vsync => {
+                                let overflowing_samples = resampled_audio
+                                    .iter()
+                                    .filter(|s| audio_tx.try_send(**s).is_err())
+                                    .count();
+                                if overflowing_samples > 0 {
+                                    log::trace!("Buffer overflow: {overflowing_samples} samples");
+                                    rate_counter.tick("Audio overflow");
+                                }
+                            }
                         }
                     }
 
+                    if let FramePacing::Vsync = frame_pacing {
+                        #[cfg(feature = "debug")]
+                        puffin::profile_scope!("wait for vsync");
+
+                        vsync_pacer.wait_for_frame();
+                    }
+
                     {
                         #[cfg(feature = "debug")]
                         puffin::profile_scope!("advance");
 
-                        rate_counter.tick("Frame");
                         audio_buffer.clear();
-                        let frame = frame_buffer.push_ref();
-                        if frame.is_err() {
-                            //TODO: If we get in a bad sync with vsync and drop a lot of frames then perhaps we can do something to yank things in place again?
-                            rate_counter.tick("Dropped frame");
-                        }
                         for command in command_rx.try_iter() {
                             use EmulatorCommand::*;
                             match command {
                                 Reset(hard) => {
-                                    nes_state.lock().unwrap().reset(hard);
+                                    nes_region = Settings::current_mut().get_nes_region().clone();
+                                    let mut nes_state = nes_state.lock().unwrap();
+                                    nes_state.set_region(&nes_region);
+                                    nes_state.reset(hard);
+                                    vsync_pacer.set_region(&nes_region);
                                 }
                                 SetSpeed(speed) => {
                                     nes_state.lock().unwrap().set_speed(speed);
                                 }
+                                SetMixer(mixer_settings) => {
+                                    mixer.set_settings(&mixer_settings);
+                                }
+                                SetAudioSender(new_audio_tx) => {
+                                    audio_tx = new_audio_tx;
+                                }
+                                SetPaused(new_paused) => {
+                                    paused = new_paused;
+                                }
+                                SetTurboFrames(turbo_frames) => {
+                                    nes_state.lock().unwrap().set_turbo_frames(turbo_frames);
+                                }
                             }
                         }
-                        nes_state.lock().unwrap().advance(
-                            *inputs.read().unwrap(),
-                            &mut NESBuffers {
-                                video: frame.ok().as_deref_mut(),
-                                audio: Some(&mut audio_buffer),
-                            },
-                        );
+                        let mut nes_state = nes_state.lock().unwrap();
+                        if paused && nes_state.can_pause() {
+                            // Keep feeding the audio (it might be what's pacing this loop) but leave the NES and the screen as is
+                            audio_buffer.resize(
+                                (SAMPLE_RATE / nes_region.to_fps()) as usize,
+                                [0.0; CHANNEL_COUNT],
+                            );
+                        } else {
+                            rate_counter.tick("Frame");
+                            let frame = frame_buffer.push_ref();
+                            if frame.is_err() {
+                                // NOTE: Use `FramePacing::Vsync` to lock the emulation to the display and avoid this
+                                rate_counter.tick("Dropped frame");
+                            }
+                            nes_state.advance(
+                                *inputs.read().unwrap(),
+                                &mut NESBuffers {
+                                    video: frame.ok().as_deref_mut(),
+                                    audio: Some(&mut audio_buffer),
+                                },
+                            );
+                            *applied_inputs.write().unwrap() = nes_state.last_inputs();
+                        }
+                        speed = nes_state.speed();
                     }
 
                     if let Some(report) = rate_counter.report() {
@@ -141,7 +237,15 @@ impl Emulator {
 pub trait NesStateHandler {
     fn advance(&mut self, joypad_state: [JoypadState; MAX_PLAYERS], buffers: &mut NESBuffers);
     fn reset(&mut self, hard: bool);
+    /// The region the next reset starts in
+    fn set_region(&mut self, region: &NesRegion);
     fn set_speed(&mut self, speed: f32);
+    fn speed(&self) -> f32;
+    fn set_turbo_frames(&mut self, turbo_frames: u8);
+    /// The buttons of each player the NES saw on the last frame (local and remote when playing online)
+    fn last_inputs(&self) -> [JoypadState; MAX_PLAYERS];
+    /// If the emulation can be paused right now (f.ex not while playing online)
+    fn can_pause(&self) -> bool;
     fn save_sram(&self) -> Option<&[u8]>;
     #[cfg(feature = "netplay")]
     fn frame(&self) -> u32;
@@ -162,6 +266,14 @@ impl NesRegion {
             NesRegion::Dendy => 50.006_977,
         }
     }
+
+    /// Audio resampling adjustment that makes audio paced emulation run at the most common display refresh rate (60hz)
+    pub fn audio_pacing_adjustment(&self) -> f32 {
+        match self {
+            NesRegion::Ntsc => self.to_fps() / 60.0,
+            _ => 1.0,
+        }
+    }
 }
 
 pub struct NESBuffers<'a> {
@@ -205,7 +317,8 @@ impl DerefMut for NESVideoFrame {
     }
 }
 
-pub struct NESAudioFrame(Vec<f32>);
+/// The separate NES audio channels, they're mixed to stereo by the `Mixer` in the emulator loop
+pub struct NESAudioFrame(Vec<ChannelSample>);
 impl NESAudioFrame {
     pub fn new() -> NESAudioFrame {
         Self(Vec::new())
@@ -213,7 +326,7 @@ impl NESAudioFrame {
 }
 
 impl Deref for NESAudioFrame {
-    type Target = Vec<f32>;
+    type Target = Vec<ChannelSample>;
     fn deref(&self) -> &Self::Target {
         &self.0
     }
@@ -0,0 +1,90 @@
+use std::{sync::mpsc::Receiver, time::Duration};
+
+use serde::Deserialize;
+
+use super::NesRegion;
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub enum FramePacing {
+    /// The audio device is the clock. The emulator runs a frame whenever there is room in the audio buffer.
+    #[default]
+    Audio,
+    /// The display is the clock. The emulator runs in step with the vsync and the audio is resampled to match.
+    Vsync,
+}
+
+// How far off (in %) the display can be from the NES framerate and still be locked to it
+const MAX_LOCK_DEVIATION: f32 = 0.01;
+// Don't try to catch up more than this many frames (f.ex after the window has been hidden)
+const MAX_FRAMES_BEHIND: u32 = 4;
+
+/// Paces the emulation using the time between presented frames sent from the render loop.
+/// If the display refreshes at (a multiple of) roughly the NES framerate the frames are locked to the refreshes,
+/// otherwise the frames are spread out over the refreshes as evenly as possible.
+pub struct VsyncPacer {
+    rx: Receiver<Duration>,
+    nes_frame_duration: Duration,
+    refresh_duration: Duration,
+    refreshes: u32,
+    accumulated: Duration,
+}
+
+impl VsyncPacer {
+    pub fn new(rx: Receiver<Duration>, region: &NesRegion) -> Self {
+        let nes_frame_duration = Duration::from_secs_f32(1.0 / region.to_fps());
+        Self {
+            rx,
+            nes_frame_duration,
+            refresh_duration: nes_frame_duration,
+            refreshes: 0,
+            accumulated: Duration::ZERO,
+        }
+    }
+
+    pub fn set_region(&mut self, region: &NesRegion) {
+        self.nes_frame_duration = Duration::from_secs_f32(1.0 / region.to_fps());
+    }
+
+    fn locked_refreshes_per_frame(&self) -> Option<u32> {
+        let refreshes_per_frame = (self.nes_frame_duration.as_secs_f32()
+            / self.refresh_duration.as_secs_f32())
+        .round()
+        .max(1.0);
+        let locked_frame_duration = self.refresh_duration.mul_f32(refreshes_per_frame);
+        let deviation =
+            (locked_frame_duration.as_secs_f32() / self.nes_frame_duration.as_secs_f32() - 1.0)
+                .abs();
+        (deviation < MAX_LOCK_DEVIATION).then_some(refreshes_per_frame as u32)
+    }
+
+    /// Blocks until it's time to emulate the next frame
+    pub fn wait_for_frame(&mut self) {
+        loop {
+            if let Some(refreshes_per_frame) = self.locked_refreshes_per_frame() {
+                if self.refreshes >= refreshes_per_frame {
+                    self.refreshes -= refreshes_per_frame;
+                    self.accumulated = Duration::ZERO;
+                    return;
+                }
+            } else if self.accumulated >= self.nes_frame_duration {
+                self.accumulated -= self.nes_frame_duration;
+                self.refreshes = 0;
+                return;
+            }
+
+            match self.rx.recv() {
+                Ok(elapsed) => {
+                    self.refresh_duration =
+                        self.refresh_duration.mul_f32(0.95) + elapsed.mul_f32(0.05);
+                    self.refreshes += 1;
+                    self.accumulated = (self.accumulated + elapsed)
+                        .min(self.nes_frame_duration * MAX_FRAMES_BEHIND);
+                }
+                Err(_) => {
+                    // The render loop is gone, nothing left to pace against
+                    return;
+                }
+            }
+        }
+    }
+}
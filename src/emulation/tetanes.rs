@@ -3,7 +3,7 @@ use std::io::Cursor;
 use anyhow::Result;
 
 use tetanes_core::{
-    apu::filter::FilterChain,
+    apu::Sample,
     common::{NesRegion, Regional, Reset, ResetKind},
     control_deck::{Config, ControlDeck, HeadlessMode, MapperRevisionsConfig},
     cpu::Cpu,
@@ -15,14 +15,24 @@ use tetanes_core::{
 
 use super::{NESBuffers, NesStateHandler, NTSC_PAL, SAMPLE_RATE};
 use crate::{
+    audio::mixer::ChannelSample,
     bundle::Bundle,
-    input::JoypadState,
+    input::{settings::InputSettings, JoypadState},
     settings::{Settings, MAX_PLAYERS},
 };
 
 #[derive(Clone)]
 pub struct TetanesNesState {
     control_deck: ControlDeck,
+    region: crate::emulation::NesRegion,
+    speed: f32,
+    sample_clock: f32,
+    /// The channel outputs summed over every CPU cycle since the last audio sample
+    channel_sums: ChannelSample,
+    summed_cycles: f32,
+    turbo_frames: u8,
+    /// The buttons the NES saw on the last frame
+    last_inputs: [JoypadState; MAX_PLAYERS],
 }
 
 trait ToTetanesRegion {
@@ -40,15 +50,10 @@ impl ToTetanesRegion for crate::emulation::NesRegion {
 }
 
 impl TetanesNesState {
-    pub fn start_rom(
-        rom: &[u8],
-        load_sram: bool,
-        region: &crate::emulation::NesRegion,
-    ) -> Result<Self> {
-        let region = region.to_tetanes_region();
-        let config = Config {
+    fn config(region: &crate::emulation::NesRegion) -> Config {
+        Config {
             filter: VideoFilter::Pixellate,
-            region,
+            region: region.to_tetanes_region(),
             ram_state: RamState::Random,
             four_player: FourPlayer::Disabled,
             zapper: false,
@@ -60,7 +65,33 @@ impl TetanesNesState {
             data_dir: Config::default_data_dir(),
             mapper_revisions: MapperRevisionsConfig::default(),
             emulate_ppu_warmup: false,
-        };
+        }
+    }
+
+    /// Identifies how the emulator is set up, netplay needs the same setup on every machine
+    pub fn config_hash(region: &crate::emulation::NesRegion) -> String {
+        let mut config = Self::config(region);
+        // Where the files are kept doesn't affect the emulation and the cheats are compared on their own
+        config.data_dir = Default::default();
+        config.genie_codes = vec![];
+        format!("{:x}", md5::compute(format!("{config:?}")))
+    }
+
+    /// The Game Genie codes the emulator runs with
+    pub fn cheats(region: &crate::emulation::NesRegion) -> Vec<String> {
+        Self::config(region)
+            .genie_codes
+            .iter()
+            .map(|code| format!("{code:?}"))
+            .collect()
+    }
+
+    pub fn start_rom(
+        rom: &[u8],
+        load_sram: bool,
+        region: &crate::emulation::NesRegion,
+    ) -> Result<Self> {
+        let config = Self::config(region);
         log::debug!("Starting ROM with configuration {config:?}");
         let mut control_deck = ControlDeck::with_config(config);
         //control_deck.set_cycle_accurate(false); //TODO: Add as a bundle config?
@@ -84,10 +115,74 @@ impl TetanesNesState {
             }
         }
 
-        control_deck.set_region(region);
-        let mut s = Self { control_deck };
-        s.set_speed(1.0); // Trigger the correct sample rate
-        Ok(s)
+        control_deck.set_region(region.to_tetanes_region());
+        // The channels are sampled and mixed by us (see `clock_frame_with_channels`)
+        control_deck.cpu_mut().bus.apu.skip_mixing = true;
+        Ok(Self {
+            control_deck,
+            region: region.clone(),
+            speed: 1.0,
+            sample_clock: 0.0,
+            channel_sums: Default::default(),
+            summed_cycles: 0.0,
+            turbo_frames: InputSettings::default_turbo_frames(),
+            last_inputs: [JoypadState(0); MAX_PLAYERS],
+        })
+    }
+
+    /// The region the ROM was started in
+    pub fn region(&self) -> &crate::emulation::NesRegion {
+        &self.region
+    }
+
+    /// The output level of each APU channel (and the expansion audio) right now
+    fn channel_outputs(&self) -> ChannelSample {
+        let bus = &self.control_deck.cpu().bus;
+        let apu = &bus.apu;
+        [
+            apu.pulse1.output(),
+            apu.pulse2.output(),
+            apu.triangle.output(),
+            apu.noise.output(),
+            apu.dmc.output(),
+            bus.mapper.output(),
+        ]
+    }
+
+    /// Clocks a frame while sampling the separate channels.
+    /// Always generates audio at the same rate, speed changes and output rates are handled by the resampler.
+    /// Each sample is the average output over its sample period, which keeps the high frequencies
+    /// of the pulse and noise channels from aliasing.
+    fn clock_frame_with_channels(&mut self, audio: &mut Vec<ChannelSample>) -> Result<usize> {
+        let sample_period =
+            Cpu::region_clock_rate(self.control_deck.cpu().bus.apu.region) / SAMPLE_RATE;
+        let frame_number = self.control_deck.frame_number();
+        let mut cycles = 0;
+        while self.control_deck.frame_number() == frame_number {
+            let instr_cycles = self.control_deck.clock_instr()?;
+            cycles += instr_cycles;
+
+            let outputs = self.channel_outputs();
+            for (sum, output) in self.channel_sums.iter_mut().zip(outputs) {
+                *sum += output * instr_cycles as f32;
+            }
+            self.summed_cycles += instr_cycles as f32;
+
+            self.sample_clock += instr_cycles as f32;
+            while self.sample_clock >= sample_period {
+                self.sample_clock -= sample_period;
+                if self.summed_cycles > 0.0 {
+                    let summed_cycles = self.summed_cycles;
+                    audio.push(self.channel_sums.map(|sum| sum / summed_cycles));
+                    self.channel_sums = Default::default();
+                    self.summed_cycles = 0.0;
+                } else {
+                    // More than one sample period passed during the instruction
+                    audio.push(outputs);
+                }
+            }
+        }
+        Ok(cycles)
     }
 
     pub fn clock_frame_into(&mut self, buffers: &mut NESBuffers) -> Result<usize> {
@@ -95,9 +190,12 @@ impl TetanesNesState {
         puffin::profile_function!();
 
         self.control_deck.cpu_mut().bus.ppu.skip_rendering = false;
-        //self.control_deck.cpu_mut().bus.apu.skip_mixing = false;
 
-        let cycles = self.control_deck.clock_frame()?;
+        let cycles = if let Some(audio) = &mut buffers.audio {
+            self.clock_frame_with_channels(audio)?
+        } else {
+            self.control_deck.clock_frame()?
+        };
         if let Some(video) = &mut buffers.video {
             #[cfg(feature = "debug")]
             puffin::profile_scope!("copy buffers");
@@ -115,20 +213,30 @@ impl TetanesNesState {
                         .clone_from_slice(&NTSC_PAL[palette_index..palette_index + 3]);
                 });
         }
-        if let Some(audio) = &mut buffers.audio {
-            audio.extend_from_slice(self.control_deck.cpu().bus.audio_samples());
-        }
 
         self.control_deck.clear_audio_samples();
         Ok(cycles)
     }
 
+    /// Serializes the complete machine state
+    pub fn save_state(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self.control_deck.cpu())
+            .map_err(|err| fs::Error::SerializationFailed(err.to_string()))?)
+    }
+
+    /// Restores a machine state made with `save_state`
+    pub fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        let state = bincode::deserialize(state)
+            .map_err(|err| fs::Error::DeserializationFailed(err.to_string()))?;
+        self.control_deck.load_cpu(state);
+        Ok(())
+    }
+
     pub fn clock_frame_ahead_into(&mut self, buffers: &mut NESBuffers) -> Result<usize> {
         #[cfg(feature = "debug")]
         puffin::profile_function!();
 
         self.control_deck.cpu_mut().bus.ppu.skip_rendering = true;
-        //self.control_deck.cpu_mut().bus.apu.skip_mixing = true;
         // Clock current frame and discard video
         {
             #[cfg(feature = "debug")]
@@ -164,26 +272,35 @@ impl TetanesNesState {
 impl NesStateHandler for TetanesNesState {
     fn set_speed(&mut self, speed: f32) {
         let speed = speed.max(0.005);
-        let apu = &mut self.control_deck.cpu_mut().bus.apu;
-        let target_sample_rate = match apu.region {
-            // Downsample a tiny bit extra to match the most common screen refresh rate (60hz)
-            NesRegion::Ntsc => SAMPLE_RATE * (crate::emulation::NesRegion::Ntsc.to_fps() / 60.0),
-            _ => SAMPLE_RATE,
-        };
-
-        let new_sample_rate = target_sample_rate * (1.0 / speed);
-        let new_sample_period = Cpu::region_clock_rate(apu.region) / new_sample_rate;
-
-        if apu.sample_period != new_sample_period {
+        if self.speed != speed {
             log::debug!("Change emulation speed to {speed}x");
-            apu.filter_chain = FilterChain::new(apu.region, new_sample_rate);
-            apu.sample_period = new_sample_period;
+            self.speed = speed;
         }
     }
 
+    fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    fn set_turbo_frames(&mut self, turbo_frames: u8) {
+        self.turbo_frames = turbo_frames;
+    }
+
+    fn last_inputs(&self) -> [JoypadState; MAX_PLAYERS] {
+        self.last_inputs
+    }
+
     fn advance(&mut self, joypad_state: [JoypadState; MAX_PLAYERS], buffers: &mut NESBuffers) {
-        *self.control_deck.joypad_mut(Player::One) = Joypad::from_bytes((*joypad_state[0]).into());
-        *self.control_deck.joypad_mut(Player::Two) = Joypad::from_bytes((*joypad_state[1]).into());
+        // Turbo is based on the emulated frame (and not wall-clock time) to keep it deterministic
+        let frame = self.control_deck.frame_number();
+        let buttons = joypad_state.map(|state| state.to_nes_buttons(frame, self.turbo_frames));
+        *self.control_deck.joypad_mut(Player::One) = Joypad::from_bytes(buttons[0].into());
+        *self.control_deck.joypad_mut(Player::Two) = Joypad::from_bytes(buttons[1].into());
+        self.last_inputs = buttons.map(|b| JoypadState(b.into()));
 
         self.clock_frame_ahead_into(buffers)
             .expect("NES to clock a frame");
@@ -210,7 +327,12 @@ impl NesStateHandler for TetanesNesState {
         };
         //Set the region in case it has been changed since last start/reset
         self.control_deck
-            .set_region(Settings::current_mut().get_nes_region().to_tetanes_region());
+            .set_region(self.region.to_tetanes_region());
         self.control_deck.reset(kind);
+        self.control_deck.cpu_mut().bus.apu.skip_mixing = true;
+    }
+
+    fn set_region(&mut self, region: &crate::emulation::NesRegion) {
+        self.region = region.clone();
     }
 }
@@ -4,6 +4,52 @@ pub trait ToGamepadButton {
     fn to_gamepad_button(&self) -> Option<GamepadButton>;
 }
 
+pub trait ToGamepadAxis {
+    fn to_gamepad_axis(&self) -> GamepadAxis;
+}
+
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum GamepadAxis {
+    LeftX,
+    LeftY,
+    RightX,
+    RightY,
+    TriggerLeft,
+    TriggerRight,
+    /// An axis of a joystick without a game controller mapping
+    Joystick(u8),
+}
+
+impl GamepadAxis {
+    /// The virtual buttons for the negative and positive direction of the axis
+    pub fn to_gamepad_buttons(&self) -> (Option<GamepadButton>, GamepadButton) {
+        match self {
+            GamepadAxis::LeftX => (
+                Some(GamepadButton::LeftStickLeft),
+                GamepadButton::LeftStickRight,
+            ),
+            GamepadAxis::LeftY => (
+                Some(GamepadButton::LeftStickUp),
+                GamepadButton::LeftStickDown,
+            ),
+            GamepadAxis::RightX => (
+                Some(GamepadButton::RightStickLeft),
+                GamepadButton::RightStickRight,
+            ),
+            GamepadAxis::RightY => (
+                Some(GamepadButton::RightStickUp),
+                GamepadButton::RightStickDown,
+            ),
+            GamepadAxis::TriggerLeft => (None, GamepadButton::LeftTrigger),
+            GamepadAxis::TriggerRight => (None, GamepadButton::RightTrigger),
+            GamepadAxis::Joystick(axis) => (
+                Some(GamepadButton::JoystickAxisMinus(*axis)),
+                GamepadButton::JoystickAxisPlus(*axis),
+            ),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub enum GamepadButton {
     A,
@@ -27,6 +73,43 @@ pub enum GamepadButton {
     Paddle3,
     Paddle4,
     Touchpad,
+
+    // Virtual buttons for the analog sticks and triggers, pressed when moved past the deadzone
+    LeftStickUp,
+    LeftStickDown,
+    LeftStickLeft,
+    LeftStickRight,
+    RightStickUp,
+    RightStickDown,
+    RightStickLeft,
+    RightStickRight,
+    LeftTrigger,
+    RightTrigger,
+
+    // Raw buttons, axes and hats of joysticks without a game controller mapping
+    JoystickButton(u8),
+    JoystickAxisMinus(u8),
+    JoystickAxisPlus(u8),
+    JoystickHatUp(u8),
+    JoystickHatDown(u8),
+    JoystickHatLeft(u8),
+    JoystickHatRight(u8),
+}
+
+impl GamepadButton {
+    /// If the button comes from a raw joystick rather than a mapped game controller
+    pub fn is_joystick(&self) -> bool {
+        matches!(
+            self,
+            GamepadButton::JoystickButton(_)
+                | GamepadButton::JoystickAxisMinus(_)
+                | GamepadButton::JoystickAxisPlus(_)
+                | GamepadButton::JoystickHatUp(_)
+                | GamepadButton::JoystickHatDown(_)
+                | GamepadButton::JoystickHatLeft(_)
+                | GamepadButton::JoystickHatRight(_)
+        )
+    }
 }
 
 impl std::fmt::Display for GamepadButton {
@@ -46,6 +129,23 @@ impl std::fmt::Display for GamepadButton {
             GamepadButton::DPadDown => write!(f, "Down"),
             GamepadButton::DPadLeft => write!(f, "Left"),
             GamepadButton::DPadRight => write!(f, "Right"),
+            GamepadButton::LeftStickUp => write!(f, "Left Stick Up"),
+            GamepadButton::LeftStickDown => write!(f, "Left Stick Down"),
+            GamepadButton::LeftStickLeft => write!(f, "Left Stick Left"),
+            GamepadButton::LeftStickRight => write!(f, "Left Stick Right"),
+            GamepadButton::RightStickUp => write!(f, "Right Stick Up"),
+            GamepadButton::RightStickDown => write!(f, "Right Stick Down"),
+            GamepadButton::RightStickLeft => write!(f, "Right Stick Left"),
+            GamepadButton::RightStickRight => write!(f, "Right Stick Right"),
+            GamepadButton::LeftTrigger => write!(f, "Trigger Left"),
+            GamepadButton::RightTrigger => write!(f, "Trigger Right"),
+            GamepadButton::JoystickButton(button) => write!(f, "Button {button}"),
+            GamepadButton::JoystickAxisMinus(axis) => write!(f, "Axis {axis}-"),
+            GamepadButton::JoystickAxisPlus(axis) => write!(f, "Axis {axis}+"),
+            GamepadButton::JoystickHatUp(hat) => write!(f, "Hat {hat} Up"),
+            GamepadButton::JoystickHatDown(hat) => write!(f, "Hat {hat} Down"),
+            GamepadButton::JoystickHatLeft(hat) => write!(f, "Hat {hat} Left"),
+            GamepadButton::JoystickHatRight(hat) => write!(f, "Hat {hat} Right"),
 
             //TODO: Better names for the rest?
             _ => write!(f, "{self:?}"),
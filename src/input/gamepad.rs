@@ -1,4 +1,7 @@
-use super::{buttons::GamepadButton, InputId, JoypadMapping, JoypadState};
+use super::{
+    buttons::{GamepadAxis, GamepadButton},
+    InputId, JoypadMapping, JoypadState,
+};
 use std::collections::HashSet;
 
 pub type JoypadGamepadMapping = JoypadMapping<GamepadButton>;
@@ -23,6 +26,10 @@ pub enum GamepadEvent {
     ControllerRemoved {
         which: InputId,
     },
+    /// A joystick was connected, it's only used if it has no game controller mapping
+    JoystickAdded {
+        which: InputId,
+    },
     ButtonDown {
         which: InputId,
         button: GamepadButton,
@@ -31,6 +38,20 @@ pub enum GamepadEvent {
         which: InputId,
         button: GamepadButton,
     },
+    AxisMotion {
+        which: InputId,
+        axis: GamepadAxis,
+        /// -1.0 to 1.0 for the sticks and 0.0 to 1.0 for the triggers
+        value: f32,
+    },
+    HatMotion {
+        which: InputId,
+        hat: u8,
+        up: bool,
+        down: bool,
+        left: bool,
+        right: bool,
+    },
 }
 
 pub trait ToGamepadEvent {
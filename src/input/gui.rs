@@ -1,12 +1,20 @@
+use std::sync::mpsc::Sender;
+
 use crate::{
-    input::{JoypadButton, JoypadState},
+    emulation::EmulatorCommand,
+    input::{JoypadButton, JoypadInput, JoypadState},
     main_view::gui::{GuiComponent, GuiEvent},
     settings::Settings,
 };
-use egui::{Color32, Grid, RichText, Ui};
+use egui::{Color32, Grid, RichText, Slider, Ui};
 use serde::Deserialize;
 
-use super::{settings::InputSettings, InputConfiguration, Inputs, MapRequest};
+use super::{
+    hotkeys::{HotkeyAction, HotkeyKind, HotkeyRequest},
+    settings::InputSettings,
+    socd::SocdPolicy,
+    InputConfiguration, Inputs, MapRequest,
+};
 
 #[derive(Deserialize, Debug)]
 pub struct InputButtonsVoca {
@@ -42,13 +50,17 @@ impl Default for InputButtonsVoca {
 pub struct InputsGui {
     pub inputs: Inputs,
     mapping_request: Option<MapRequest>,
+    hotkey_request: Option<HotkeyRequest>,
+    emulator_tx: Sender<EmulatorCommand>,
 }
 
 impl InputsGui {
-    pub fn new(inputs: Inputs) -> Self {
+    pub fn new(inputs: Inputs, emulator_tx: Sender<EmulatorCommand>) -> Self {
         Self {
             mapping_request: None,
+            hotkey_request: None,
             inputs,
+            emulator_tx,
         }
     }
 
@@ -79,23 +91,60 @@ impl InputsGui {
             });
 
         let input_configuration = input_settings.get_selected_configuration_mut(player);
+        ui.horizontal(|ui| {
+            ui.label("Opposing directions")
+                .on_hover_text("What happens when Left+Right or Up+Down are held at the same time");
+            egui::ComboBox::from_id_source(format!("socd-{}", player))
+                .selected_text(input_configuration.socd.to_string())
+                .show_ui(ui, |ui| {
+                    for policy in SocdPolicy::ALL {
+                        ui.selectable_value(
+                            &mut input_configuration.socd,
+                            policy,
+                            policy.to_string(),
+                        );
+                    }
+                });
+        });
         Grid::new(format!("joypadmap_grid_{}", player))
             .num_columns(2)
             .striped(true)
             .show(ui, |ui| {
                 use JoypadButton::*;
                 [Up, Down, Left, Right, Select, Start, B, A]
-                    .iter()
-                    .for_each(|&button| {
+                    .map(JoypadInput::Button)
+                    .into_iter()
+                    .chain([B, A].map(JoypadInput::Turbo))
+                    .for_each(|input| {
                         Self::button_map_ui(
                             mapping_request,
                             ui,
                             input_configuration,
                             joypad_state,
-                            button,
+                            input,
                         );
                     });
             });
+        ui.collapsing("More turbo buttons", |ui| {
+            Grid::new(format!("joypadmap_turbo_grid_{}", player))
+                .num_columns(2)
+                .striped(true)
+                .show(ui, |ui| {
+                    use JoypadButton::*;
+                    [Up, Down, Left, Right, Select, Start]
+                        .map(JoypadInput::Turbo)
+                        .into_iter()
+                        .for_each(|input| {
+                            Self::button_map_ui(
+                                mapping_request,
+                                ui,
+                                input_configuration,
+                                joypad_state,
+                                input,
+                            );
+                        });
+                });
+        });
     }
 
     fn button_map_ui(
@@ -103,18 +152,17 @@ impl InputsGui {
         ui: &mut Ui,
         input_configuration: &mut InputConfiguration,
         joypad_state: JoypadState,
-        button: JoypadButton,
+        input: JoypadInput,
     ) {
-        let mut text = RichText::new(format!("{button}"));
-        if joypad_state.is_pressed(button) {
+        let mut text = RichText::new(format!("{input}"));
+        if joypad_state.is_pressed(input) {
             text = text.color(Color32::from_rgb(255, 255, 255));
         }
         ui.label(text);
         match map_request {
-            Some(MapRequest {
-                input_id,
-                button: b,
-            }) if *input_id == input_configuration.id && *b == button => {
+            Some(MapRequest { input_id, input: i })
+                if *input_id == input_configuration.id && *i == input =>
+            {
                 if ui
                     .button(RichText::new("Cancel").color(Color32::from_rgb(255, 0, 0)))
                     .clicked()
@@ -125,10 +173,10 @@ impl InputsGui {
             _ => {
                 let key_to_map = match &mut input_configuration.kind {
                     crate::input::InputConfigurationKind::Keyboard(mapping) => {
-                        mapping.lookup(&button).map(|v| format!("{v}"))
+                        mapping.lookup(&input).map(|v| format!("{v}"))
                     }
                     crate::input::InputConfigurationKind::Gamepad(mapping) => {
-                        mapping.lookup(&button).map(|v| format!("{v}"))
+                        mapping.lookup(&input).map(|v| format!("{v}"))
                     }
                 }
                 .unwrap_or_else(|| "-".to_string());
@@ -136,17 +184,103 @@ impl InputsGui {
                 if ui.button(key_to_map).clicked() {
                     *map_request = Some(MapRequest {
                         input_id: input_configuration.id.clone(),
-                        button,
+                        input,
                     });
                 }
             }
         }
         ui.end_row();
     }
+
+    /// If the next key or button press is going to be mapped to a joypad button or a hotkey
+    pub fn is_mapping(&self) -> bool {
+        self.mapping_request.is_some() || self.hotkey_request.is_some()
+    }
+
+    fn hotkeys_ui(
+        ui: &mut Ui,
+        input_settings: &mut InputSettings,
+        hotkey_request: &mut Option<HotkeyRequest>,
+    ) {
+        let hotkeys = input_settings.get_hotkeys();
+        Grid::new("hotkeys_grid")
+            .num_columns(3)
+            .striped(true)
+            .show(ui, |ui| {
+                for action in HotkeyAction::ALL {
+                    ui.label(format!("{action}"));
+                    for kind in [HotkeyKind::Keyboard, HotkeyKind::Gamepad] {
+                        match hotkey_request {
+                            Some(request) if request.action == action && request.kind == kind => {
+                                if ui
+                                    .button(
+                                        RichText::new("Cancel").color(Color32::from_rgb(255, 0, 0)),
+                                    )
+                                    .clicked()
+                                {
+                                    *hotkey_request = None;
+                                }
+                            }
+                            _ => {
+                                let text = hotkeys
+                                    .get(action)
+                                    .iter()
+                                    .filter(|h| h.kind() == kind)
+                                    .map(|h| h.to_string())
+                                    .collect::<Vec<_>>()
+                                    .join(", ");
+                                let response = ui
+                                    .button(if text.is_empty() {
+                                        "-".to_string()
+                                    } else {
+                                        text
+                                    })
+                                    .on_hover_text("Right click to clear");
+                                if response.clicked() {
+                                    *hotkey_request = Some(HotkeyRequest::new(action, kind));
+                                } else if response.secondary_clicked() {
+                                    hotkeys.clear(action, kind);
+                                }
+                            }
+                        }
+                    }
+                    ui.end_row();
+                }
+            });
+
+        let hotkeys = input_settings.get_hotkeys().clone();
+        for action in HotkeyAction::ALL {
+            for hotkey in hotkeys.get(action) {
+                let conflicts = hotkeys.conflicts(action, hotkey, &input_settings.configurations);
+                if !conflicts.is_empty() {
+                    ui.label(
+                        RichText::new(format!(
+                            "{hotkey} ({action}) is also used for {}",
+                            conflicts.join(", ")
+                        ))
+                        .color(Color32::DARK_RED),
+                    );
+                }
+            }
+        }
+
+        if ui.button("Reset hotkeys").clicked() {
+            *input_settings.get_hotkeys() = InputSettings::default_hotkeys();
+        }
+    }
 }
 
 impl GuiComponent for InputsGui {
     fn handle_event(&mut self, gui_event: &GuiEvent) {
+        if let Some(request) = &mut self.hotkey_request {
+            if let Some(hotkey) = request.advance(gui_event) {
+                Settings::current_mut()
+                    .input
+                    .get_hotkeys()
+                    .set(request.action, hotkey);
+                self.hotkey_request = None;
+            }
+        }
         self.inputs.advance(gui_event);
     }
 
@@ -187,6 +321,32 @@ impl GuiComponent for InputsGui {
             });
         });
 
+        ui.horizontal(|ui| {
+            ui.label("Turbo speed");
+            let turbo_frames = input_settings.get_turbo_frames();
+            if ui
+                .add(Slider::new(turbo_frames, 1..=8).suffix(" frames"))
+                .on_hover_text("For how many frames turbo buttons are pressed and then released")
+                .changed()
+            {
+                let _ = self
+                    .emulator_tx
+                    .send(EmulatorCommand::SetTurboFrames(*turbo_frames));
+            }
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Analog deadzone");
+            ui.add(Slider::new(&mut input_settings.deadzone, 5..=95).suffix("%"))
+                .on_hover_text(
+                    "How far an analog stick or trigger has to move to count as pressed",
+                );
+        });
+
+        ui.collapsing("Hotkeys", |ui| {
+            Self::hotkeys_ui(ui, input_settings, &mut self.hotkey_request);
+        });
+
         self.inputs
             .remap_configuration(&mut self.mapping_request, input_settings);
     }
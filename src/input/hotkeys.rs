@@ -0,0 +1,410 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    buttons::GamepadButton,
+    gamepad::GamepadEvent,
+    keys::{KeyCode, Modifiers},
+    InputConfiguration, InputConfigurationKind, InputId, KeyEvent,
+};
+use crate::{main_view::gui::GuiEvent, settings::Settings};
+
+/// Things that can be done with a hotkey, outside of playing the game
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HotkeyAction {
+    Menu,
+    Fullscreen,
+    Reset,
+    Screenshot,
+    VolumeUp,
+    VolumeDown,
+    InputDisplay,
+}
+
+impl HotkeyAction {
+    pub const ALL: [HotkeyAction; 7] = [
+        HotkeyAction::Menu,
+        HotkeyAction::Fullscreen,
+        HotkeyAction::Reset,
+        HotkeyAction::Screenshot,
+        HotkeyAction::VolumeUp,
+        HotkeyAction::VolumeDown,
+        HotkeyAction::InputDisplay,
+    ];
+}
+
+impl std::fmt::Display for HotkeyAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HotkeyAction::Menu => write!(f, "Menu"),
+            HotkeyAction::Fullscreen => write!(f, "Fullscreen"),
+            HotkeyAction::Reset => write!(f, "Reset"),
+            HotkeyAction::Screenshot => write!(f, "Screenshot"),
+            HotkeyAction::VolumeUp => write!(f, "Volume up"),
+            HotkeyAction::VolumeDown => write!(f, "Volume down"),
+            HotkeyAction::InputDisplay => write!(f, "Input display"),
+        }
+    }
+}
+
+/// A key and the modifiers that have to be held together with it
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub struct KeyChord {
+    pub key: KeyCode,
+    #[serde(default)]
+    pub ctrl: bool,
+    #[serde(default)]
+    pub alt: bool,
+    #[serde(default)]
+    pub shift: bool,
+    /// The "windows" key on PC and "command" key on Mac
+    #[serde(default)]
+    pub logo: bool,
+}
+
+impl KeyChord {
+    pub fn new(key: KeyCode, modifiers: Modifiers) -> Self {
+        Self {
+            key,
+            ctrl: modifiers.contains(Modifiers::CTRL),
+            alt: modifiers.contains(Modifiers::ALT),
+            shift: modifiers.contains(Modifiers::SHIFT),
+            logo: modifiers.contains(Modifiers::LOGO),
+        }
+    }
+
+    /// Chord with the platform's usual modifier for shortcuts (command on Mac, control elsewhere)
+    fn command(key: KeyCode) -> Self {
+        #[cfg(target_os = "macos")]
+        let modifiers = Modifiers::LOGO;
+        #[cfg(not(target_os = "macos"))]
+        let modifiers = Modifiers::CTRL;
+        Self::new(key, modifiers)
+    }
+
+    fn matches(&self, modifiers: Modifiers, key: KeyCode) -> bool {
+        *self == Self::new(key, modifiers)
+    }
+}
+
+impl std::fmt::Display for KeyChord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.ctrl {
+            write!(f, "Ctrl+")?;
+        }
+        if self.alt {
+            write!(f, "Alt+")?;
+        }
+        if self.shift {
+            write!(f, "Shift+")?;
+        }
+        if self.logo {
+            #[cfg(target_os = "macos")]
+            write!(f, "Cmd+")?;
+            #[cfg(not(target_os = "macos"))]
+            write!(f, "Win+")?;
+        }
+        write!(f, "{}", self.key)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub enum Hotkey {
+    Keyboard(KeyChord),
+    /// Gamepad buttons that have to be held at the same time
+    Gamepad(Vec<GamepadButton>),
+}
+
+impl Hotkey {
+    pub fn kind(&self) -> HotkeyKind {
+        match self {
+            Hotkey::Keyboard(_) => HotkeyKind::Keyboard,
+            Hotkey::Gamepad(_) => HotkeyKind::Gamepad,
+        }
+    }
+
+    /// True if pressing one of the hotkeys also presses the other, f.ex `Guide` and `Back+Guide`
+    fn overlaps(&self, other: &Hotkey) -> bool {
+        match (self, other) {
+            (Hotkey::Gamepad(buttons), Hotkey::Gamepad(other_buttons)) => {
+                buttons.iter().all(|b| other_buttons.contains(b))
+                    || other_buttons.iter().all(|b| buttons.contains(b))
+            }
+            _ => self == other,
+        }
+    }
+}
+
+impl std::fmt::Display for Hotkey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Hotkey::Keyboard(chord) => write!(f, "{chord}"),
+            Hotkey::Gamepad(buttons) => write!(
+                f,
+                "{}",
+                buttons
+                    .iter()
+                    .map(|b| b.to_string())
+                    .collect::<Vec<_>>()
+                    .join("+")
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotkeyKind {
+    Keyboard,
+    Gamepad,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub struct Hotkeys(BTreeMap<HotkeyAction, Vec<Hotkey>>);
+
+impl Default for Hotkeys {
+    fn default() -> Self {
+        use Hotkey::*;
+        #[cfg(target_os = "macos")]
+        let fullscreen = vec![
+            Keyboard(KeyChord::new(KeyCode::KeyF, Modifiers::LOGO)),
+            Keyboard(KeyChord::new(KeyCode::Enter, Modifiers::LOGO)),
+        ];
+        #[cfg(not(target_os = "macos"))]
+        let fullscreen = vec![
+            Keyboard(KeyChord::new(KeyCode::Enter, Modifiers::ALT)),
+            Keyboard(KeyChord::new(KeyCode::F11, Modifiers::empty())),
+        ];
+
+        Self(BTreeMap::from([
+            (
+                HotkeyAction::Menu,
+                vec![
+                    Keyboard(KeyChord::new(KeyCode::Escape, Modifiers::empty())),
+                    Gamepad(vec![GamepadButton::Guide]),
+                ],
+            ),
+            (HotkeyAction::Fullscreen, fullscreen),
+            (
+                HotkeyAction::Reset,
+                vec![Keyboard(KeyChord::command(KeyCode::KeyR))],
+            ),
+            (
+                HotkeyAction::Screenshot,
+                vec![Keyboard(KeyChord::new(KeyCode::F12, Modifiers::empty()))],
+            ),
+            (
+                HotkeyAction::VolumeUp,
+                vec![Keyboard(KeyChord::command(KeyCode::Equal))],
+            ),
+            (
+                HotkeyAction::VolumeDown,
+                vec![Keyboard(KeyChord::command(KeyCode::Minus))],
+            ),
+        ]))
+    }
+}
+
+impl Hotkeys {
+    pub fn get(&self, action: HotkeyAction) -> &[Hotkey] {
+        self.0
+            .get(&action)
+            .map(|h| h.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Replaces the hotkeys of the same kind for the action
+    pub fn set(&mut self, action: HotkeyAction, hotkey: Hotkey) {
+        let hotkeys = self.0.entry(action).or_default();
+        hotkeys.retain(|h| h.kind() != hotkey.kind());
+        hotkeys.push(hotkey);
+    }
+
+    pub fn clear(&mut self, action: HotkeyAction, kind: HotkeyKind) {
+        if let Some(hotkeys) = self.0.get_mut(&action) {
+            hotkeys.retain(|h| h.kind() != kind);
+        }
+    }
+
+    fn find_key(&self, modifiers: Modifiers, key: KeyCode) -> Option<HotkeyAction> {
+        self.0.iter().find_map(|(action, hotkeys)| {
+            hotkeys
+                .iter()
+                .any(|h| matches!(h, Hotkey::Keyboard(chord) if chord.matches(modifiers, key)))
+                .then_some(*action)
+        })
+    }
+
+    /// The action of the largest combo that `button` completes
+    fn find_buttons(
+        &self,
+        pressed: &HashSet<GamepadButton>,
+        button: &GamepadButton,
+    ) -> Option<HotkeyAction> {
+        self.0
+            .iter()
+            .flat_map(|(action, hotkeys)| hotkeys.iter().map(move |h| (action, h)))
+            .filter_map(|(action, hotkey)| match hotkey {
+                Hotkey::Gamepad(buttons)
+                    if buttons.contains(button) && buttons.iter().all(|b| pressed.contains(b)) =>
+                {
+                    Some((action, buttons.len()))
+                }
+                _ => None,
+            })
+            .max_by_key(|(_, len)| *len)
+            .map(|(action, _)| *action)
+    }
+
+    /// Describes what else pressing `hotkey` would do, f.ex other hotkeys or joypad buttons using the same keys
+    pub fn conflicts(
+        &self,
+        action: HotkeyAction,
+        hotkey: &Hotkey,
+        configurations: &BTreeMap<InputId, InputConfiguration>,
+    ) -> Vec<String> {
+        let mut conflicts: Vec<String> = self
+            .0
+            .iter()
+            .filter(|(other_action, hotkeys)| {
+                **other_action != action && hotkeys.iter().any(|h| h.overlaps(hotkey))
+            })
+            .map(|(other_action, _)| format!("{other_action}"))
+            .collect();
+
+        for configuration in configurations.values() {
+            let inputs = match (&configuration.kind, hotkey) {
+                (InputConfigurationKind::Keyboard(mapping), Hotkey::Keyboard(chord)) => {
+                    mapping.reverse_lookup(&chord.key)
+                }
+                (InputConfigurationKind::Gamepad(mapping), Hotkey::Gamepad(buttons)) => buttons
+                    .iter()
+                    .flat_map(|button| mapping.reverse_lookup(button))
+                    .collect(),
+                _ => HashSet::new(),
+            };
+            for input in inputs {
+                conflicts.push(format!("{input} on {}", configuration.name));
+            }
+        }
+        conflicts
+    }
+}
+
+/// Keeps track of held modifiers and gamepad buttons to tell when a hotkey is pressed
+pub struct HotkeyState {
+    modifiers: Modifiers,
+    pressed_buttons: HashMap<InputId, HashSet<GamepadButton>>,
+}
+
+impl HotkeyState {
+    pub fn new() -> Self {
+        Self {
+            modifiers: Modifiers::empty(),
+            pressed_buttons: HashMap::new(),
+        }
+    }
+
+    /// Returns the action of the hotkey the event completes, if any
+    pub fn advance(&mut self, gui_event: &GuiEvent) -> Option<HotkeyAction> {
+        match gui_event {
+            GuiEvent::Keyboard(KeyEvent::ModifiersChanged(modifiers)) => {
+                self.modifiers = *modifiers;
+                None
+            }
+            GuiEvent::Keyboard(KeyEvent::Pressed(key)) => Settings::current()
+                .input
+                .hotkeys()
+                .find_key(self.modifiers, *key),
+            GuiEvent::Gamepad(GamepadEvent::ButtonDown { which, button }) => {
+                let pressed = self.pressed_buttons.entry(which.clone()).or_default();
+                pressed.insert(*button);
+                Settings::current()
+                    .input
+                    .hotkeys()
+                    .find_buttons(pressed, button)
+            }
+            GuiEvent::Gamepad(GamepadEvent::ButtonUp { which, button }) => {
+                if let Some(pressed) = self.pressed_buttons.get_mut(which) {
+                    pressed.remove(button);
+                }
+                None
+            }
+            GuiEvent::Gamepad(GamepadEvent::ControllerRemoved { which }) => {
+                self.pressed_buttons.remove(which);
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A request to bind a new hotkey to an action, listens for the next key chord or gamepad combo
+#[derive(Debug)]
+pub struct HotkeyRequest {
+    pub action: HotkeyAction,
+    pub kind: HotkeyKind,
+    modifiers: Modifiers,
+    held: HashSet<GamepadButton>,
+    captured: Vec<GamepadButton>,
+}
+
+impl HotkeyRequest {
+    pub fn new(action: HotkeyAction, kind: HotkeyKind) -> Self {
+        Self {
+            action,
+            kind,
+            modifiers: Modifiers::empty(),
+            held: HashSet::new(),
+            captured: Vec::new(),
+        }
+    }
+
+    fn is_modifier(key: &KeyCode) -> bool {
+        use KeyCode::*;
+        matches!(
+            key,
+            ShiftLeft
+                | ShiftRight
+                | ControlLeft
+                | ControlRight
+                | AltLeft
+                | AltRight
+                | SuperLeft
+                | SuperRight
+                | Meta
+        )
+    }
+
+    /// Returns the new hotkey once a key is pressed, or all buttons of a gamepad combo are released again
+    pub fn advance(&mut self, gui_event: &GuiEvent) -> Option<Hotkey> {
+        match (self.kind, gui_event) {
+            (HotkeyKind::Keyboard, GuiEvent::Keyboard(KeyEvent::ModifiersChanged(modifiers))) => {
+                self.modifiers = *modifiers;
+                None
+            }
+            // NOTE: Escape is used for main menu navigation
+            (HotkeyKind::Keyboard, GuiEvent::Keyboard(KeyEvent::Pressed(key)))
+                if !Self::is_modifier(key) && *key != KeyCode::Escape =>
+            {
+                Some(Hotkey::Keyboard(KeyChord::new(*key, self.modifiers)))
+            }
+            // NOTE: Guide is used for main menu navigation
+            (HotkeyKind::Gamepad, GuiEvent::Gamepad(GamepadEvent::ButtonDown { button, .. }))
+                if *button != GamepadButton::Guide =>
+            {
+                self.held.insert(*button);
+                if !self.captured.contains(button) {
+                    self.captured.push(*button);
+                }
+                None
+            }
+            (HotkeyKind::Gamepad, GuiEvent::Gamepad(GamepadEvent::ButtonUp { button, .. })) => {
+                self.held.remove(button);
+                (self.held.is_empty() && !self.captured.is_empty())
+                    .then(|| Hotkey::Gamepad(std::mem::take(&mut self.captured)))
+            }
+            _ => None,
+        }
+    }
+}
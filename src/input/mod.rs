@@ -5,6 +5,7 @@ use self::{
     keys::{KeyCode, Modifiers},
     sdl2_impl::Sdl2Gamepads,
     settings::InputSettings,
+    socd::{SocdCleaner, SocdPolicy},
 };
 use crate::{
     bundle::Bundle,
@@ -17,10 +18,12 @@ use std::{collections::HashSet, fmt::Debug, ops::Deref};
 pub mod buttons;
 pub mod gamepad;
 pub mod gui;
+pub mod hotkeys;
 pub mod keyboard;
 pub mod keys;
 pub mod sdl2_impl;
 pub mod settings;
+pub mod socd;
 
 type GamepadImpl = Sdl2Gamepads;
 
@@ -63,6 +66,32 @@ impl std::fmt::Display for JoypadButton {
     }
 }
 
+/// A button or the turbo (autofire) variant of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum JoypadInput {
+    Button(JoypadButton),
+    Turbo(JoypadButton),
+}
+
+impl JoypadInput {
+    /// The bits of this input in a `JoypadState`
+    fn bits(&self) -> u16 {
+        match self {
+            JoypadInput::Button(button) => *button as u16,
+            JoypadInput::Turbo(button) => (*button as u16) << 8,
+        }
+    }
+}
+
+impl std::fmt::Display for JoypadInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoypadInput::Button(button) => write!(f, "{button}"),
+            JoypadInput::Turbo(button) => write!(f, "Turbo {button}"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub struct JoypadMapping<KeyType> {
     pub up: Option<KeyType>,
@@ -75,13 +104,58 @@ pub struct JoypadMapping<KeyType> {
 
     pub b: Option<KeyType>,
     pub a: Option<KeyType>,
+
+    #[serde(default)]
+    pub turbo: TurboMapping<KeyType>,
 }
 
-impl<KeyType> JoypadMapping<KeyType>
-where
-    KeyType: PartialEq + Debug,
-{
-    pub fn lookup(&mut self, button: &JoypadButton) -> &mut Option<KeyType> {
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub struct TurboMapping<KeyType> {
+    pub up: Option<KeyType>,
+    pub down: Option<KeyType>,
+    pub left: Option<KeyType>,
+    pub right: Option<KeyType>,
+
+    pub select: Option<KeyType>,
+    pub start: Option<KeyType>,
+
+    pub b: Option<KeyType>,
+    pub a: Option<KeyType>,
+}
+
+impl<KeyType> Default for TurboMapping<KeyType> {
+    fn default() -> Self {
+        Self {
+            up: None,
+            down: None,
+            left: None,
+            right: None,
+            select: None,
+            start: None,
+            b: None,
+            a: None,
+        }
+    }
+}
+
+impl<KeyType> Default for JoypadMapping<KeyType> {
+    fn default() -> Self {
+        Self {
+            up: None,
+            down: None,
+            left: None,
+            right: None,
+            select: None,
+            start: None,
+            b: None,
+            a: None,
+            turbo: TurboMapping::default(),
+        }
+    }
+}
+
+impl<KeyType> TurboMapping<KeyType> {
+    fn lookup(&mut self, button: &JoypadButton) -> &mut Option<KeyType> {
         match button {
             JoypadButton::Up => &mut self.up,
             JoypadButton::Down => &mut self.down,
@@ -95,23 +169,57 @@ where
             JoypadButton::A => &mut self.a,
         }
     }
+}
 
-    fn reverse_lookup(&self, key: &KeyType) -> HashSet<JoypadButton> {
+impl<KeyType> JoypadMapping<KeyType>
+where
+    KeyType: PartialEq + Debug,
+{
+    pub fn lookup(&mut self, input: &JoypadInput) -> &mut Option<KeyType> {
+        match input {
+            JoypadInput::Button(button) => match button {
+                JoypadButton::Up => &mut self.up,
+                JoypadButton::Down => &mut self.down,
+                JoypadButton::Left => &mut self.left,
+                JoypadButton::Right => &mut self.right,
+
+                JoypadButton::Select => &mut self.select,
+                JoypadButton::Start => &mut self.start,
+
+                JoypadButton::B => &mut self.b,
+                JoypadButton::A => &mut self.a,
+            },
+            JoypadInput::Turbo(button) => self.turbo.lookup(button),
+        }
+    }
+
+    fn reverse_lookup(&self, key: &KeyType) -> HashSet<JoypadInput> {
+        use JoypadButton::*;
+        use JoypadInput::*;
+        let turbo = &self.turbo;
         [
-            (JoypadButton::Up, &self.up),
-            (JoypadButton::Down, &self.down),
-            (JoypadButton::Left, &self.left),
-            (JoypadButton::Right, &self.right),
-            (JoypadButton::Select, &self.select),
-            (JoypadButton::Start, &self.start),
-            (JoypadButton::B, &self.b),
-            (JoypadButton::A, &self.a),
+            (Button(Up), &self.up),
+            (Button(Down), &self.down),
+            (Button(Left), &self.left),
+            (Button(Right), &self.right),
+            (Button(Select), &self.select),
+            (Button(Start), &self.start),
+            (Button(B), &self.b),
+            (Button(A), &self.a),
+            (Turbo(Up), &turbo.up),
+            (Turbo(Down), &turbo.down),
+            (Turbo(Left), &turbo.left),
+            (Turbo(Right), &turbo.right),
+            (Turbo(Select), &turbo.select),
+            (Turbo(Start), &turbo.start),
+            (Turbo(B), &turbo.b),
+            (Turbo(A), &turbo.a),
         ]
         .into_iter()
-        .fold(HashSet::new(), |mut acc, (joypad_button, mapping)| {
+        .fold(HashSet::new(), |mut acc, (joypad_input, mapping)| {
             if let Some(a_key) = mapping {
                 if key == a_key {
-                    acc.insert(joypad_button);
+                    acc.insert(joypad_input);
                 }
             }
             acc
@@ -119,20 +227,21 @@ where
     }
 
     fn calculate_state(&self, keys: &HashSet<KeyType>) -> JoypadState {
-        JoypadState(keys.iter().fold(0_u8, |mut acc, key| {
-            for button in self.reverse_lookup(key) {
-                acc |= button as u8;
+        JoypadState(keys.iter().fold(0_u16, |mut acc, key| {
+            for input in self.reverse_lookup(key) {
+                acc |= input.bits();
             }
             acc
         }))
     }
 }
 
+/// The held buttons in the low byte and the held turbo buttons in the high byte
 #[derive(Debug, Clone, Copy)]
-pub struct JoypadState(pub u8);
+pub struct JoypadState(pub u16);
 
 impl Deref for JoypadState {
-    type Target = u8;
+    type Target = u16;
 
     fn deref(&self) -> &Self::Target {
         &self.0
@@ -140,8 +249,18 @@ impl Deref for JoypadState {
 }
 
 impl JoypadState {
-    pub fn is_pressed(&self, button: JoypadButton) -> bool {
-        self.deref() & (button as u8) != 0
+    pub fn is_pressed(&self, input: JoypadInput) -> bool {
+        self.deref() & input.bits() != 0
+    }
+
+    /// The buttons the NES sees on a given frame, turbo buttons are pressed for `turbo_frames` frames and then released for as many
+    pub fn to_nes_buttons(&self, frame: u32, turbo_frames: u8) -> u8 {
+        let [buttons, turbo] = self.0.to_le_bytes();
+        if (frame / turbo_frames.max(1) as u32) % 2 == 0 {
+            buttons | turbo
+        } else {
+            buttons
+        }
     }
 }
 
@@ -155,6 +274,9 @@ pub struct InputConfiguration {
     pub id: InputId,
     pub name: String,
     pub kind: InputConfigurationKind,
+    /// How to handle opposing directions being held at the same time
+    #[serde(default)]
+    pub socd: SocdPolicy,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq)]
@@ -165,12 +287,13 @@ pub enum InputConfigurationKind {
 #[derive(Debug)]
 pub struct MapRequest {
     input_id: InputId,
-    button: JoypadButton,
+    input: JoypadInput,
 }
 
 pub struct Inputs {
     keyboards: Keyboards,
     gamepads: GamepadImpl,
+    socd_cleaners: [SocdCleaner; MAX_PLAYERS],
     pub joypads: [JoypadState; MAX_PLAYERS],
 }
 
@@ -181,6 +304,7 @@ impl Inputs {
         Self {
             keyboards,
             gamepads,
+            socd_cleaners: Default::default(),
             joypads: [JoypadState(0), JoypadState(0)],
         }
     }
@@ -193,17 +317,16 @@ impl Inputs {
             GuiEvent::Gamepad(gamepad_event) => {
                 self.gamepads.advance(gamepad_event);
             }
+            GuiEvent::Audio(_) => return,
         }
         let input_settings = &mut Settings::current_mut().input;
         input_settings.reset_selected_disconnected_inputs(self);
 
-        let pad1 =
-            self.get_joypad_for_input_configuration(input_settings.get_selected_configuration(0));
-        let pad2 =
-            self.get_joypad_for_input_configuration(input_settings.get_selected_configuration(1));
-
-        self.joypads[0] = pad1;
-        self.joypads[1] = pad2;
+        for player in 0..MAX_PLAYERS {
+            let input_conf = input_settings.get_selected_configuration(player);
+            let pad = self.get_joypad_for_input_configuration(input_conf);
+            self.joypads[player] = self.socd_cleaners[player].clean(input_conf.socd, pad);
+        }
     }
 
     pub fn get_joypad(&self, player: usize) -> JoypadState {
@@ -252,12 +375,12 @@ impl Inputs {
                 .configurations
                 .get_mut(&map_request.input_id.clone())
             {
-                let button = &map_request.button;
+                let input = &map_request.input;
                 let input_configuration_id = input_configuration.id.clone();
                 match &mut input_configuration.kind {
                     InputConfigurationKind::Keyboard(mapping) => {
                         if let Some(code) = self.keyboards.pressed_keys.iter().next() {
-                            let _ = mapping.lookup(button).insert(*code);
+                            let _ = mapping.lookup(input).insert(*code);
                             remapped = true;
                         }
                     }
@@ -269,7 +392,7 @@ impl Inputs {
                             if let Some(new_button) = state.get_pressed_buttons().iter().next() {
                                 //If there's any button pressed, use the first found... unless it's the reserved "Guide" button used for bringing up the main menu
                                 if !matches!(new_button, GamepadButton::Guide) {
-                                    let _ = mapping.lookup(button).insert(*new_button);
+                                    let _ = mapping.lookup(input).insert(*new_button);
                                     remapped = true;
                                 }
                             }
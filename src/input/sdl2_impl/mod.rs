@@ -1,11 +1,15 @@
-use super::buttons::ToGamepadButton;
+use super::buttons::{GamepadAxis, ToGamepadAxis, ToGamepadButton};
 use super::{buttons::GamepadButton, InputId, JoypadState};
 use super::{InputConfiguration, ToInputId};
+use crate::bundle::Bundle;
 use crate::input::{self, InputConfigurationKind};
 use crate::settings::Settings;
 use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
 
-use sdl2::{controller::GameController, GameControllerSubsystem};
+use sdl2::{
+    controller::GameController, joystick::Joystick, GameControllerSubsystem, JoystickSubsystem,
+};
 
 use super::gamepad::{GamepadEvent, GamepadState, Gamepads, JoypadGamepadMapping, ToGamepadEvent};
 
@@ -46,9 +50,48 @@ impl GamepadState for Sdl2GamepadState {
         }
     }
 }
+
+/// A joystick SDL has no game controller mapping for, its raw buttons, axes and hats are used as is
+pub struct Sdl2JoystickState {
+    pub pressed_buttons: HashSet<GamepadButton>,
+    joystick: Joystick,
+}
+
+impl Sdl2JoystickState {
+    pub fn new(joystick: Joystick) -> Self {
+        Self {
+            pressed_buttons: HashSet::new(),
+            joystick,
+        }
+    }
+}
+
+impl GamepadState for Sdl2JoystickState {
+    fn is_connected(&self) -> bool {
+        self.joystick.attached()
+    }
+
+    fn get_pressed_buttons(&self) -> &HashSet<GamepadButton> {
+        &self.pressed_buttons
+    }
+
+    fn toogle_button(&mut self, button: &GamepadButton, pressed: bool) {
+        if pressed {
+            self.pressed_buttons.insert(*button);
+        } else {
+            self.pressed_buttons.remove(button);
+        }
+    }
+}
+
 pub struct Sdl2Gamepads {
     game_controller_subsystem: GameControllerSubsystem,
+    joystick_subsystem: JoystickSubsystem,
     all: HashMap<InputId, Box<dyn GamepadState>>,
+    /// SDL instance id -> the id of the input configuration
+    instances: HashMap<InputId, InputId>,
+    /// SDL instance ids of the joysticks used without a game controller mapping
+    joysticks: HashSet<InputId>,
 }
 
 impl Gamepads for Sdl2Gamepads {
@@ -68,95 +111,243 @@ impl Gamepads for Sdl2Gamepads {
         match gamepad_event {
             GamepadEvent::ControllerAdded { which, .. } => {
                 if let Some(conf) = self.setup_gamepad_config(which.clone()) {
-                    let input_settings = &mut Settings::current_mut().input;
-                    // Automatically select a gamepad if it's connected and keyboard is currently selected.
-                    if let InputConfigurationKind::Keyboard(_) =
-                        input_settings.get_selected_configuration(0).kind
-                    {
-                        input_settings.selected[0] = conf.id;
-                    } else if let InputConfigurationKind::Keyboard(_) =
-                        input_settings.get_selected_configuration(1).kind
-                    {
-                        input_settings.selected[1] = conf.id;
-                    }
+                    Self::auto_select(conf);
                 } else {
                     log::error!("Could not setup controller {:?}", which);
                 }
             }
-            // Event::ControllerDeviceRemoved { which, .. } => {
-            //     self.get_or_create_gamepad(which).disconnected = true;
-            // }
+            GamepadEvent::JoystickAdded { which } => {
+                let is_game_controller = which
+                    .parse()
+                    .is_ok_and(|idx| self.game_controller_subsystem.is_game_controller(idx));
+                // Game controllers are set up by their own event
+                if !is_game_controller {
+                    if let Some(conf) = self.setup_joystick_config(which.clone()) {
+                        Self::auto_select(conf);
+                    } else {
+                        log::error!("Could not setup joystick {:?}", which);
+                    }
+                }
+            }
+            GamepadEvent::ControllerRemoved { which } => {
+                self.joysticks.remove(which);
+                if let Some(gamepad_id) = self.instances.remove(which) {
+                    log::debug!("Gamepad {gamepad_id} removed");
+                    self.all.remove(&gamepad_id);
+                }
+            }
             GamepadEvent::ButtonDown { which, button, .. } => {
-                if let Some(gamepad_state) = self.get_gamepad(which.clone()) {
+                if let Some(gamepad_state) = self.get_gamepad(which.clone(), button) {
                     gamepad_state.toogle_button(button, true);
-                } else {
-                    log::warn!("Button down on unmapped gamepad {:?}", which);
                 }
             }
             GamepadEvent::ButtonUp { which, button, .. } => {
-                if let Some(gamepad_state) = self.get_gamepad(which.clone()) {
+                if let Some(gamepad_state) = self.get_gamepad(which.clone(), button) {
                     gamepad_state.toogle_button(button, false);
-                } else {
-                    log::warn!("Button up on unmapped gamepad {:?}", which);
                 }
             }
-            _ => (),
+            GamepadEvent::AxisMotion { which, axis, value } => {
+                let deadzone = Settings::current().input.deadzone();
+                let (negative, positive) = axis.to_gamepad_buttons();
+                if let Some(gamepad_state) = self.get_gamepad(which.clone(), &positive) {
+                    if let Some(negative) = negative {
+                        gamepad_state.toogle_button(&negative, *value < -deadzone);
+                    }
+                    gamepad_state.toogle_button(&positive, *value > deadzone);
+                }
+            }
+            GamepadEvent::HatMotion {
+                which,
+                hat,
+                up,
+                down,
+                left,
+                right,
+            } => {
+                let hat = *hat;
+                if let Some(gamepad_state) =
+                    self.get_gamepad(which.clone(), &GamepadButton::JoystickHatUp(hat))
+                {
+                    gamepad_state.toogle_button(&GamepadButton::JoystickHatUp(hat), *up);
+                    gamepad_state.toogle_button(&GamepadButton::JoystickHatDown(hat), *down);
+                    gamepad_state.toogle_button(&GamepadButton::JoystickHatLeft(hat), *left);
+                    gamepad_state.toogle_button(&GamepadButton::JoystickHatRight(hat), *right);
+                }
+            }
         }
     }
 }
 impl Sdl2Gamepads {
-    fn to_gamepad_id(id: &InputId) -> String {
-        format!("01-gamepad-{}", id)
+    /// Automatically select a gamepad if it's connected and keyboard is currently selected.
+    fn auto_select(conf: InputConfiguration) {
+        let input_settings = &mut Settings::current_mut().input;
+        if let InputConfigurationKind::Keyboard(_) =
+            input_settings.get_selected_configuration(0).kind
+        {
+            input_settings.selected[0] = conf.id;
+        } else if let InputConfigurationKind::Keyboard(_) =
+            input_settings.get_selected_configuration(1).kind
+        {
+            input_settings.selected[1] = conf.id;
+        }
     }
 
-    pub fn new(game_controller_subsystem: GameControllerSubsystem) -> Self {
+    /// The id gamepads got before they were identified by GUID (the SDL instance id)
+    fn to_legacy_gamepad_id(instance_id: &InputId) -> String {
+        format!("01-gamepad-{}", instance_id)
+    }
+
+    /// A stable id for the physical gamepad.
+    /// `slot` separates identical gamepads, the first one connected gets slot 0 and so on.
+    fn to_gamepad_id(guid: &str, name: &str, slot: usize) -> String {
+        format!("01-gamepad-{guid}-{name}-{slot}")
+    }
+
+    /// Registers the game controller mappings of the bundle and the player's own `gamecontrollerdb.txt`.
+    /// This needs to happen before any controllers are opened.
+    pub fn load_mappings(game_controller_subsystem: &GameControllerSubsystem) {
+        match game_controller_subsystem
+            .load_mappings_from_read(&mut Cursor::new(&Bundle::current().gamecontrollerdb))
+        {
+            Ok(count) => log::debug!("Loaded {count} game controller mappings from the bundle"),
+            Err(e) => log::error!(
+                "Could not load the bundled game controller mappings: {:?}",
+                e
+            ),
+        }
+
+        let user_mappings = Bundle::current().settings_path.join("gamecontrollerdb.txt");
+        if user_mappings.exists() {
+            match game_controller_subsystem.load_mappings(&user_mappings) {
+                Ok(count) => {
+                    log::info!("Loaded {count} game controller mappings from {user_mappings:?}")
+                }
+                Err(e) => log::error!("Could not load {user_mappings:?}: {:?}", e),
+            }
+        }
+    }
+
+    pub fn new(
+        game_controller_subsystem: GameControllerSubsystem,
+        joystick_subsystem: JoystickSubsystem,
+    ) -> Self {
         Sdl2Gamepads {
             game_controller_subsystem,
+            joystick_subsystem,
             all: HashMap::new(),
+            instances: HashMap::new(),
+            joysticks: HashSet::new(),
         }
     }
 
-    fn get_gamepad(&mut self, id: InputId) -> Option<&mut Box<dyn GamepadState>> {
-        self.all.get_mut(&Self::to_gamepad_id(&id))
+    /// Raw joystick buttons only apply to raw joysticks, and mapped buttons only to game controllers
+    /// (SDL sends both kinds of events for game controllers).
+    fn get_gamepad(
+        &mut self,
+        instance_id: InputId,
+        button: &GamepadButton,
+    ) -> Option<&mut Box<dyn GamepadState>> {
+        if self.joysticks.contains(&instance_id) != button.is_joystick() {
+            return None;
+        }
+        self.instances
+            .get(&instance_id)
+            .and_then(|gamepad_id| self.all.get_mut(gamepad_id))
     }
 
     fn setup_gamepad_config(&mut self, input_id: InputId) -> Option<InputConfiguration> {
-        if let Some(found_controller) =
-            (0..self.game_controller_subsystem.num_joysticks().unwrap_or(0)).find_map(|id| {
-                if input_id == id.to_input_id()
-                    && self.game_controller_subsystem.is_game_controller(id)
-                {
-                    match self.game_controller_subsystem.open(id) {
-                        Ok(c) => Some(c),
-                        Err(e) => {
-                            log::error!("Failed to open controller {:?}", e);
-                            None
-                        }
-                    }
-                } else {
-                    None
-                }
-            })
+        let device_index = input_id.parse().ok()?;
+        if !self
+            .game_controller_subsystem
+            .is_game_controller(device_index)
         {
-            let instance_id = found_controller.instance_id().to_input_id();
-            let gamepad_id = Self::to_gamepad_id(&instance_id);
-            self.all.insert(
-                gamepad_id.clone(),
-                Box::new(Sdl2GamepadState::new(found_controller)),
-            );
-            let input_settings = &mut Settings::current_mut().input;
-            let conf = input_settings.get_or_create_config(
+            return None;
+        }
+        let controller = self
+            .game_controller_subsystem
+            .open(device_index)
+            .inspect_err(|e| log::error!("Failed to open controller {:?}", e))
+            .ok()?;
+        let instance_id = controller.instance_id().to_input_id();
+        let name = controller.name();
+        let mapping = Settings::current().input.default_gamepad_mapping;
+        Some(self.register_gamepad(
+            device_index,
+            instance_id,
+            &name,
+            format!("🎮 {name}"),
+            mapping,
+            Box::new(Sdl2GamepadState::new(controller)),
+        ))
+    }
+
+    fn setup_joystick_config(&mut self, input_id: InputId) -> Option<InputConfiguration> {
+        let device_index = input_id.parse().ok()?;
+        let joystick = self
+            .joystick_subsystem
+            .open(device_index)
+            .inspect_err(|e| log::error!("Failed to open joystick {:?}", e))
+            .ok()?;
+        let instance_id = joystick.instance_id().to_input_id();
+        let name = joystick.name();
+        log::info!("Joystick {name} has no game controller mapping, using its raw buttons");
+        self.joysticks.insert(instance_id.clone());
+        // Raw joysticks have no standard layout, so the buttons have to be mapped by the player
+        Some(self.register_gamepad(
+            device_index,
+            instance_id,
+            &name,
+            format!("🕹 {name}"),
+            JoypadGamepadMapping::default(),
+            Box::new(Sdl2JoystickState::new(joystick)),
+        ))
+    }
+
+    fn register_gamepad(
+        &mut self,
+        device_index: u32,
+        instance_id: InputId,
+        name: &str,
+        configuration_name: String,
+        mapping: JoypadGamepadMapping,
+        state: Box<dyn GamepadState>,
+    ) -> InputConfiguration {
+        let guid = match self.joystick_subsystem.device_guid(device_index) {
+            Ok(guid) => guid.string(),
+            Err(e) => {
+                log::warn!("Could not get the GUID of gamepad {device_index}: {:?}", e);
+                "unknown".to_string()
+            }
+        };
+        let slot = (0..)
+            .find(|slot| {
+                self.get_gamepad_by_input_id(&Self::to_gamepad_id(&guid, name, *slot))
+                    .is_none()
+            })
+            .expect("a free slot");
+        let gamepad_id = Self::to_gamepad_id(&guid, name, slot);
+        log::debug!("Gamepad {gamepad_id} added (instance {instance_id})");
+
+        self.instances
+            .insert(instance_id.clone(), gamepad_id.clone());
+        self.all.insert(gamepad_id.clone(), state);
+        let input_settings = &mut Settings::current_mut().input;
+        // Instance ids are handed out in connection order, so a legacy configuration can only be
+        // told apart from the ones of other gamepads when this is the only gamepad connected.
+        if self.all.len() == 1 {
+            input_settings.migrate_config(&Self::to_legacy_gamepad_id(&instance_id), &gamepad_id);
+        }
+        input_settings
+            .get_or_create_config(
                 gamepad_id.clone(),
                 input::InputConfiguration {
-                    name: format!("🎮 Gamepad {}", instance_id),
+                    name: configuration_name,
                     id: gamepad_id,
-                    kind: InputConfigurationKind::Gamepad(input_settings.default_gamepad_mapping),
+                    kind: InputConfigurationKind::Gamepad(mapping),
+                    socd: Default::default(),
                 },
-            );
-            Some(conf.clone())
-        } else {
-            None
-        }
+            )
+            .clone()
     }
 }
 
@@ -185,6 +376,59 @@ impl ToGamepadEvent for sdl2::event::Event {
                     which: which.to_input_id(),
                     button,
                 }),
+            sdl2::event::Event::JoyDeviceAdded { which, .. } => Some(GamepadEvent::JoystickAdded {
+                which: which.to_input_id(),
+            }),
+            sdl2::event::Event::JoyDeviceRemoved { which, .. } => {
+                Some(GamepadEvent::ControllerRemoved {
+                    which: which.to_input_id(),
+                })
+            }
+            sdl2::event::Event::JoyButtonDown {
+                which, button_idx, ..
+            } => Some(GamepadEvent::ButtonDown {
+                which: which.to_input_id(),
+                button: GamepadButton::JoystickButton(*button_idx),
+            }),
+            sdl2::event::Event::JoyButtonUp {
+                which, button_idx, ..
+            } => Some(GamepadEvent::ButtonUp {
+                which: which.to_input_id(),
+                button: GamepadButton::JoystickButton(*button_idx),
+            }),
+            sdl2::event::Event::JoyAxisMotion {
+                which,
+                axis_idx,
+                value,
+                ..
+            } => Some(GamepadEvent::AxisMotion {
+                which: which.to_input_id(),
+                axis: GamepadAxis::Joystick(*axis_idx),
+                value: (*value as f32 / i16::MAX as f32).max(-1.0),
+            }),
+            sdl2::event::Event::JoyHatMotion {
+                which,
+                hat_idx,
+                state,
+                ..
+            } => {
+                use sdl2::joystick::HatState::*;
+                Some(GamepadEvent::HatMotion {
+                    which: which.to_input_id(),
+                    hat: *hat_idx,
+                    up: matches!(state, Up | LeftUp | RightUp),
+                    down: matches!(state, Down | LeftDown | RightDown),
+                    left: matches!(state, Left | LeftUp | LeftDown),
+                    right: matches!(state, Right | RightUp | RightDown),
+                })
+            }
+            sdl2::event::Event::ControllerAxisMotion {
+                which, axis, value, ..
+            } => Some(GamepadEvent::AxisMotion {
+                which: which.to_input_id(),
+                axis: axis.to_gamepad_axis(),
+                value: (*value as f32 / i16::MAX as f32).max(-1.0),
+            }),
             _ => None,
         }
     }
@@ -218,3 +462,17 @@ impl ToGamepadButton for sdl2::controller::Button {
         }
     }
 }
+
+impl ToGamepadAxis for sdl2::controller::Axis {
+    fn to_gamepad_axis(&self) -> GamepadAxis {
+        use sdl2::controller::Axis::*;
+        match self {
+            LeftX => GamepadAxis::LeftX,
+            LeftY => GamepadAxis::LeftY,
+            RightX => GamepadAxis::RightX,
+            RightY => GamepadAxis::RightY,
+            TriggerLeft => GamepadAxis::TriggerLeft,
+            TriggerRight => GamepadAxis::TriggerRight,
+        }
+    }
+}
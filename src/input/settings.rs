@@ -1,16 +1,73 @@
 use super::MAX_PLAYERS;
-use crate::input::{gamepad::JoypadGamepadMapping, InputConfiguration, InputId, Inputs};
+use crate::{
+    bundle::Bundle,
+    input::{gamepad::JoypadGamepadMapping, hotkeys::Hotkeys, InputConfiguration, InputId, Inputs},
+};
 use serde::{Deserialize, Serialize};
-use std::{collections::BTreeMap, hash::Hash};
+use std::{borrow::Cow, collections::BTreeMap, hash::Hash};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InputSettings {
     pub selected: [InputId; MAX_PLAYERS],
     pub configurations: BTreeMap<InputId, InputConfiguration>,
     pub default_gamepad_mapping: JoypadGamepadMapping,
+    /// For how many frames turbo buttons are pressed and then released
+    #[serde(default)]
+    turbo_frames: Option<u8>,
+    /// How far (in %) an analog stick or trigger has to move before it counts as pressed
+    #[serde(default = "InputSettings::default_deadzone")]
+    pub deadzone: u8,
+    #[serde(default)]
+    hotkeys: Option<Hotkeys>,
 }
 
 impl InputSettings {
+    /// The turbo rate of the bundle, this is what's used when playing online
+    pub fn default_turbo_frames() -> u8 {
+        Bundle::current()
+            .config
+            .default_settings
+            .input
+            .turbo_frames
+            .unwrap_or(2)
+    }
+
+    fn default_deadzone() -> u8 {
+        50
+    }
+
+    pub fn deadzone(&self) -> f32 {
+        self.deadzone.min(99) as f32 / 100.0
+    }
+
+    pub fn get_turbo_frames(&mut self) -> &mut u8 {
+        self.turbo_frames
+            .get_or_insert_with(InputSettings::default_turbo_frames)
+    }
+
+    pub fn default_hotkeys() -> Hotkeys {
+        Bundle::current()
+            .config
+            .default_settings
+            .input
+            .hotkeys
+            .clone()
+            .unwrap_or_default()
+    }
+
+    /// The hotkeys in use, for when they only need to be looked at
+    pub fn hotkeys(&self) -> Cow<'_, Hotkeys> {
+        self.hotkeys.as_ref().map_or_else(
+            || Cow::Owned(InputSettings::default_hotkeys()),
+            Cow::Borrowed,
+        )
+    }
+
+    pub fn get_hotkeys(&mut self) -> &mut Hotkeys {
+        self.hotkeys
+            .get_or_insert_with(InputSettings::default_hotkeys)
+    }
+
     pub fn get_or_create_config(
         &mut self,
         id: InputId,
@@ -19,6 +76,23 @@ impl InputSettings {
         self.configurations.entry(id).or_insert_with(|| default)
     }
 
+    /// Moves a configuration to a new id (unless there already is one with that id)
+    pub fn migrate_config(&mut self, old_id: &InputId, new_id: &InputId) {
+        if self.configurations.contains_key(new_id) {
+            return;
+        }
+        if let Some(mut conf) = self.configurations.remove(old_id) {
+            log::info!("Migrating input configuration {old_id} to {new_id}");
+            conf.id.clone_from(new_id);
+            self.configurations.insert(new_id.clone(), conf);
+            for selected in &mut self.selected {
+                if selected == old_id {
+                    selected.clone_from(new_id);
+                }
+            }
+        }
+    }
+
     pub fn get_selected_configuration(&self, idx: usize) -> &InputConfiguration {
         self.configurations.get(&self.selected[idx]).unwrap()
     }
@@ -48,5 +122,8 @@ impl Hash for InputSettings {
             k.hash(state);
             v.hash(state);
         }
+        self.turbo_frames.hash(state);
+        self.deadzone.hash(state);
+        self.hotkeys.hash(state);
     }
 }
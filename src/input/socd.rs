@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+
+use super::{JoypadButton, JoypadInput, JoypadState};
+
+/// What to do when opposing directions (Left+Right or Up+Down) are held at the same time (Simultaneous Opposing Cardinal Directions).
+/// A real NES controller can't do that and some games glitch or crash if it happens.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq, Default)]
+pub enum SocdPolicy {
+    /// Neither direction is pressed
+    Neutral,
+    /// The direction pressed last is the one that counts
+    #[default]
+    LastInputWins,
+    /// The direction pressed first is the one that counts
+    FirstInputWins,
+}
+
+impl SocdPolicy {
+    pub const ALL: [SocdPolicy; 3] = [
+        SocdPolicy::Neutral,
+        SocdPolicy::LastInputWins,
+        SocdPolicy::FirstInputWins,
+    ];
+}
+
+impl std::fmt::Display for SocdPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SocdPolicy::Neutral => write!(f, "Neutral"),
+            SocdPolicy::LastInputWins => write!(f, "Last input wins"),
+            SocdPolicy::FirstInputWins => write!(f, "First input wins"),
+        }
+    }
+}
+
+/// Removes opposing directions from the joypad state of one player.
+/// It has to see every state change to know in what order the directions were pressed.
+#[derive(Debug, Default)]
+pub struct SocdCleaner {
+    previous: u16,
+    /// The direction that wins on each axis (horizontal and vertical) while both are held
+    winners: [Option<JoypadButton>; 2],
+}
+
+impl SocdCleaner {
+    const AXES: [(JoypadButton, JoypadButton); 2] = [
+        (JoypadButton::Left, JoypadButton::Right),
+        (JoypadButton::Up, JoypadButton::Down),
+    ];
+
+    /// A direction is held if either the button or its turbo variant is
+    fn bits(direction: JoypadButton) -> u16 {
+        JoypadInput::Button(direction).bits() | JoypadInput::Turbo(direction).bits()
+    }
+
+    pub fn clean(&mut self, policy: SocdPolicy, state: JoypadState) -> JoypadState {
+        let raw = state.0;
+        let mut cleaned = raw;
+        for (axis, (a, b)) in Self::AXES.into_iter().enumerate() {
+            let (a_bits, b_bits) = (Self::bits(a), Self::bits(b));
+            if raw & a_bits == 0 || raw & b_bits == 0 {
+                self.winners[axis] = None;
+                continue;
+            }
+            let a_new = self.previous & a_bits == 0;
+            let b_new = self.previous & b_bits == 0;
+            let (newest, oldest) = match (a_new, b_new) {
+                (true, false) => (Some(a), Some(b)),
+                (false, true) => (Some(b), Some(a)),
+                // Pressed at the same time, or still held since the last change
+                _ => (self.winners[axis], self.winners[axis]),
+            };
+            self.winners[axis] = match policy {
+                SocdPolicy::Neutral => None,
+                SocdPolicy::LastInputWins => newest,
+                SocdPolicy::FirstInputWins => oldest,
+            };
+            cleaned &= match self.winners[axis] {
+                Some(winner) if winner == a => !b_bits,
+                Some(_) => !a_bits,
+                None => !(a_bits | b_bits),
+            };
+        }
+        self.previous = raw;
+        JoypadState(cleaned)
+    }
+}
@@ -3,7 +3,7 @@
 #![deny(clippy::all)]
 
 use audio::gui::AudioGui;
-use audio::Audio;
+use audio::{Audio, AudioBackend, ToAudioEvent};
 use bundle::Bundle;
 
 use input::gamepad::ToGamepadEvent;
@@ -12,13 +12,13 @@ use input::sdl2_impl::Sdl2Gamepads;
 use input::{Inputs, JoypadState};
 use main_view::MainView;
 
-use settings::{Settings, MAX_PLAYERS};
+use settings::{FocusLossAction, Settings, MAX_PLAYERS};
 
-use std::sync::{Arc, RwLock};
+use std::sync::{mpsc::channel, Arc, RwLock};
 use std::time::{Duration, Instant};
 use window::egui_winit_wgpu::Renderer;
 
-use emulation::{BufferPool, Emulator, SAMPLE_RATE};
+use emulation::{pacing::FramePacing, BufferPool, Emulator, EmulatorCommand};
 use integer_scaling::MINIMUM_INTEGER_SCALING_SIZE;
 
 use emulation::{NES_HEIGHT, NES_WIDTH_4_3};
@@ -26,7 +26,7 @@ use window::create_window;
 use winit::event::{Event, StartCause, WindowEvent};
 use winit::event_loop::EventLoop;
 
-use crate::main_view::gui::GuiEvent;
+use crate::main_view::gui::{GuiEvent, MainGui, MainMenuState};
 
 mod audio;
 mod bundle;
@@ -56,6 +56,17 @@ async fn main() {
         std::process::exit(0);
     }
 
+    #[cfg(feature = "netplay")]
+    if let Some(path) = std::env::args().skip_while(|arg| arg != "--replay").nth(1) {
+        match netplay::replay::run_headless(std::path::Path::new(&path)) {
+            Ok(()) => std::process::exit(0),
+            Err(e) => {
+                eprintln!("{e:?}");
+                std::process::exit(1);
+            }
+        }
+    }
+
     log::info!("NES Bundler is starting!");
 
     if let Err(e) = run().await {
@@ -81,35 +92,51 @@ async fn run() -> anyhow::Result<()> {
     let sdl_context = sdl2::init().map_err(anyhow::Error::msg)?;
     let mut sdl_event_pump = sdl_context.event_pump().map_err(anyhow::Error::msg)?;
 
+    let frame_pacing = &Bundle::current().config.frame_pacing;
+    let audio_settings = Settings::current().audio.clone();
+    let latency = Duration::from_millis(audio_settings.latency as u64);
     let mut audio = Audio::new(
         &sdl_context,
-        Duration::from_millis(Settings::current().audio.latency as u64),
-        SAMPLE_RATE as u32,
+        latency,
+        audio_settings.sample_rate,
+        AudioBackend::from_args(),
     )?;
 
+    let game_controller_subsystem = sdl_context.game_controller().map_err(anyhow::Error::msg)?;
+    Sdl2Gamepads::load_mappings(&game_controller_subsystem);
     let inputs = Inputs::new(Sdl2Gamepads::new(
-        sdl_context.game_controller().map_err(anyhow::Error::msg)?,
+        game_controller_subsystem,
+        sdl_context.joystick().map_err(anyhow::Error::msg)?,
     ));
     let audio_tx = audio.stream.start()?;
 
     let renderer = Renderer::new(window.clone()).await?;
 
-    let mut inputs_gui = InputsGui::new(inputs);
-    let mut audio_gui = AudioGui::new(audio);
-
     let emulator = Emulator::new()?;
     let shared_inputs = Arc::new(RwLock::new([JoypadState(0); MAX_PLAYERS]));
+    let applied_inputs = Arc::new(RwLock::new([JoypadState(0); MAX_PLAYERS]));
     let frame_buffer = BufferPool::new();
+    let (vsync_tx, vsync_rx) = channel();
     let (mut emulator_gui, emulator_tx) = emulator
-        .start_thread(audio_tx, shared_inputs.clone(), frame_buffer.clone())
+        .start_thread(
+            audio_tx,
+            shared_inputs.clone(),
+            applied_inputs.clone(),
+            frame_buffer.clone(),
+            vsync_rx,
+        )
         .await?;
 
-    let mut main_view = MainView::new(renderer, emulator_tx);
+    let mut inputs_gui = InputsGui::new(inputs, emulator_tx.clone());
+    let mut audio_gui = AudioGui::new(audio, emulator_tx.clone());
+    let mut main_view = MainView::new(renderer, emulator_tx.clone(), applied_inputs);
 
     let mouse_hide_timeout = Duration::from_secs(1);
     let mut last_mouse_touch = Instant::now()
         .checked_sub(mouse_hide_timeout)
         .expect("there to be an instant `mouse_hide_timeout` seconds in the past");
+    let mut last_render = Instant::now();
+    let mut paused_by_focus_loss = false;
 
     event_loop.set_control_flow(winit::event_loop::ControlFlow::Poll);
     event_loop.run(|winit_event, control_flow| {
@@ -138,6 +165,21 @@ async fn run() -> anyhow::Result<()> {
                     WindowEvent::MouseInput { .. } | WindowEvent::CursorMoved { .. } => {
                         last_mouse_touch = Instant::now();
                     }
+                    WindowEvent::Focused(true) => {
+                        audio::set_muted(false);
+                    }
+                    WindowEvent::Focused(false) => {
+                        let focus_loss = *Settings::current_mut().get_focus_loss();
+                        match focus_loss {
+                            FocusLossAction::KeepRunning => {}
+                            FocusLossAction::Mute => audio::set_muted(true),
+                            FocusLossAction::Pause => {
+                                MainGui::set_main_menu_state(MainMenuState::Main);
+                                let _ = emulator_tx.send(EmulatorCommand::SetPaused(true));
+                                paused_by_focus_loss = true;
+                            }
+                        }
+                    }
                     _ => {}
                 }
                 main_view.handle_window_event(
@@ -161,11 +203,11 @@ async fn run() -> anyhow::Result<()> {
                     .gt(&mouse_hide_timeout)),
         );
 
-        for sdl_gui_event in sdl_event_pump
-            .poll_iter()
-            .flat_map(|e| e.to_gamepad_event())
-            .map(GuiEvent::Gamepad)
-        {
+        for sdl_gui_event in sdl_event_pump.poll_iter().flat_map(|e| {
+            e.to_gamepad_event()
+                .map(GuiEvent::Gamepad)
+                .or_else(|| e.to_audio_event().map(GuiEvent::Audio))
+        }) {
             main_view.handle_gui_event(
                 &sdl_gui_event,
                 &mut audio_gui,
@@ -174,6 +216,12 @@ async fn run() -> anyhow::Result<()> {
             );
         }
 
+        if paused_by_focus_loss && !main_view.main_gui.visible() {
+            // Resume when the player closes the menu
+            let _ = emulator_tx.send(EmulatorCommand::SetPaused(false));
+            paused_by_focus_loss = false;
+        }
+
         let new_inputs = if !main_view.main_gui.visible() {
             inputs_gui.inputs.joypads
         } else {
@@ -189,6 +237,11 @@ async fn run() -> anyhow::Result<()> {
                 &mut inputs_gui,
                 &mut emulator_gui,
             );
+            if let FramePacing::Vsync = frame_pacing {
+                // Presenting blocks until vsync, so this is the time between two refreshes
+                let _ = vsync_tx.send(last_render.elapsed());
+                last_render = Instant::now();
+            }
         }
     })?;
 
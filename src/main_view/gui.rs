@@ -9,12 +9,12 @@ use egui::{
 use winit::dpi::LogicalSize;
 
 use crate::{
-    audio::gui::AudioGui,
+    audio::{gui::AudioGui, AudioEvent},
     bundle::Bundle,
     emulation::{gui::EmulatorGui, EmulatorCommand},
     gui::{esc_pressed, MenuButton},
-    input::{gamepad::GamepadEvent, gui::InputsGui, KeyEvent},
-    settings::Settings,
+    input::{gamepad::GamepadEvent, gui::InputsGui, hotkeys::HotkeyAction, KeyEvent},
+    settings::{FocusLossAction, Settings},
 };
 
 pub trait ToGuiEvent {
@@ -26,6 +26,7 @@ pub trait ToGuiEvent {
 pub enum GuiEvent {
     Keyboard(KeyEvent),
     Gamepad(GamepadEvent),
+    Audio(AudioEvent),
 }
 
 pub trait GuiComponent {
@@ -35,6 +36,9 @@ pub trait GuiComponent {
     // Runs if gui is visible
     fn ui(&mut self, _ui: &mut Ui) {}
 
+    // Runs every frame, for things drawn on top of the game
+    fn overlay(&mut self, _ctx: &Context) {}
+
     fn messages(&self) -> Option<Vec<String>> {
         None
     }
@@ -137,10 +141,6 @@ impl MainGui {
         inputs_gui: &mut InputsGui,
         emulator_gui: &mut EmulatorGui,
     ) {
-        if !self.visible() && esc_pressed(ctx) {
-            Self::set_main_menu_state(MainMenuState::Main);
-        }
-
         match Self::main_menu_state() {
             MainMenuState::Main => {
                 Self::ui_main_container(&self.window, None, ctx, |ui| {
@@ -189,6 +189,26 @@ impl MainGui {
                             inputs_gui.ui(ui);
                         }
 
+                        ui.add_space(10.0);
+                        ui.separator();
+                        ui.horizontal(|ui| {
+                            ui.label("When the window loses focus");
+                            let mut settings = Settings::current_mut();
+                            let focus_loss = settings.get_focus_loss();
+                            for action in [
+                                FocusLossAction::KeepRunning,
+                                FocusLossAction::Mute,
+                                FocusLossAction::Pause,
+                            ] {
+                                ui.radio_value(focus_loss, action, action.to_string());
+                            }
+                        });
+
+                        ui.checkbox(
+                            &mut Settings::current_mut().show_input_display,
+                            "Show the buttons pressed by each player",
+                        );
+
                         if Bundle::current().config.supported_nes_regions.len() > 1 {
                             ui.separator();
                             ui.vertical_centered(|ui| {
@@ -244,6 +264,8 @@ impl MainGui {
             MainMenuState::Closed => {}
         }
 
+        emulator_gui.overlay(ctx);
+
         egui::TopBottomPanel::top("messages")
             .show_separator_line(false)
             .frame(
@@ -267,7 +289,14 @@ impl MainGui {
                         }
                     }
                     if self.start_time.elapsed() < Duration::from_secs(5) {
-                        Self::message_ui(ui, "Press ESC for menu");
+                        if let Some(hotkey) = Settings::current()
+                            .input
+                            .hotkeys()
+                            .get(HotkeyAction::Menu)
+                            .first()
+                        {
+                            Self::message_ui(ui, format!("Press {hotkey} for menu"));
+                        }
                     }
                 });
             });
@@ -0,0 +1,61 @@
+use egui::{vec2, Color32, Pos2, Rect, Rounding, Sense, Stroke, Ui, Vec2};
+
+use crate::input::{JoypadButton, JoypadInput, JoypadState};
+
+const SIZE: Vec2 = vec2(96.0, 40.0);
+const BODY: Color32 = Color32::from_rgba_premultiplied(30, 30, 30, 200);
+const RELEASED: Color32 = Color32::from_rgb(90, 90, 90);
+const PRESSED: Color32 = Color32::from_rgb(230, 40, 40);
+
+fn color(state: JoypadState, button: JoypadButton) -> Color32 {
+    if state.is_pressed(JoypadInput::Button(button)) {
+        PRESSED
+    } else {
+        RELEASED
+    }
+}
+
+/// Draws a small NES controller with the pressed buttons lit up
+pub fn joypad_ui(ui: &mut Ui, label: &str, state: JoypadState) {
+    ui.horizontal(|ui| {
+        ui.label(egui::RichText::new(label).color(Color32::WHITE).strong());
+        let (rect, _) = ui.allocate_exact_size(SIZE, Sense::hover());
+        let painter = ui.painter_at(rect);
+        painter.rect_filled(rect, Rounding::same(4.0), BODY);
+
+        let at = |x: f32, y: f32| Pos2::new(rect.min.x + x, rect.min.y + y);
+
+        // D-pad
+        let arm = 7.0;
+        let center = at(20.0, 20.0);
+        let square = |offset: Vec2| Rect::from_center_size(center + offset, vec2(arm, arm));
+        painter.rect_filled(square(Vec2::ZERO), Rounding::ZERO, RELEASED);
+        for (button, offset) in [
+            (JoypadButton::Up, vec2(0.0, -arm)),
+            (JoypadButton::Down, vec2(0.0, arm)),
+            (JoypadButton::Left, vec2(-arm, 0.0)),
+            (JoypadButton::Right, vec2(arm, 0.0)),
+        ] {
+            painter.rect_filled(square(offset), Rounding::ZERO, color(state, button));
+        }
+
+        // Select and start
+        for (button, x) in [(JoypadButton::Select, 40.0), (JoypadButton::Start, 52.0)] {
+            painter.rect_filled(
+                Rect::from_center_size(at(x, 26.0), vec2(9.0, 4.0)),
+                Rounding::same(2.0),
+                color(state, button),
+            );
+        }
+
+        // B and A
+        for (button, x) in [(JoypadButton::B, 70.0), (JoypadButton::A, 84.0)] {
+            painter.circle(
+                at(x, 24.0),
+                5.0,
+                color(state, button),
+                Stroke::new(1.0, Color32::BLACK),
+            );
+        }
+    });
+}
@@ -1,4 +1,4 @@
-use std::sync::mpsc::Sender;
+use std::sync::{mpsc::Sender, Arc, RwLock};
 
 use egui::{load::SizedTexture, Color32, Image, Vec2};
 
@@ -8,9 +8,14 @@ use crate::{
         gui::EmulatorGui, BufferPool, EmulatorCommand, NES_HEIGHT, NES_WIDTH, NES_WIDTH_4_3,
     },
     input::{
-        buttons::GamepadButton, gamepad::GamepadEvent, gui::InputsGui, keys::Modifiers, KeyEvent,
+        buttons::GamepadButton,
+        gamepad::GamepadEvent,
+        gui::InputsGui,
+        hotkeys::{HotkeyAction, HotkeyState},
+        JoypadState,
     },
     integer_scaling::{calculate_size_corrected, MINIMUM_INTEGER_SCALING_SIZE},
+    settings::Settings,
     window::{
         egui_winit_wgpu::{texture::Texture, Renderer},
         Fullscreen,
@@ -18,12 +23,18 @@ use crate::{
     Size,
 };
 
-use self::gui::{GuiEvent, MainGui, ToGuiEvent};
+use self::gui::{GuiEvent, MainGui, MainMenuState, ToGuiEvent};
 pub mod gui;
+mod input_display;
+mod screenshot;
 
 pub struct MainView {
     pub main_gui: MainGui,
-    modifiers: Modifiers,
+    hotkey_state: HotkeyState,
+    emulator_tx: Sender<EmulatorCommand>,
+    screenshot_requested: bool,
+    /// The inputs the emulator applied on the last frame, for the input display
+    applied_inputs: Arc<RwLock<[JoypadState; MAX_PLAYERS]>>,
     nes_texture: Texture,
     renderer: Renderer,
 }
@@ -63,10 +74,17 @@ fn to_egui_event(gamepad_event: &GamepadEvent) -> Option<egui::Event> {
 }
 
 impl MainView {
-    pub fn new(mut renderer: Renderer, emulator_tx: Sender<EmulatorCommand>) -> Self {
+    pub fn new(
+        mut renderer: Renderer,
+        emulator_tx: Sender<EmulatorCommand>,
+        applied_inputs: Arc<RwLock<[JoypadState; MAX_PLAYERS]>>,
+    ) -> Self {
         Self {
-            main_gui: MainGui::new(renderer.window.clone(), emulator_tx),
-            modifiers: Modifiers::empty(),
+            main_gui: MainGui::new(renderer.window.clone(), emulator_tx.clone()),
+            hotkey_state: HotkeyState::new(),
+            emulator_tx,
+            screenshot_requested: false,
+            applied_inputs,
 
             nes_texture: Texture::new(&mut renderer, NES_WIDTH, NES_HEIGHT, Some("nes frame")),
             renderer,
@@ -103,50 +121,73 @@ impl MainView {
         inputs_gui: &mut InputsGui,
         emulator_gui: &mut EmulatorGui,
     ) {
-        use gui::GuiEvent::Keyboard;
+        let hotkey_action = self.hotkey_state.advance(gui_event);
+        let consumed = match hotkey_action {
+            Some(action) if !inputs_gui.is_mapping() => self.run_hotkey(action),
+            _ => false,
+        };
 
-        let consumed = match gui_event {
-            Keyboard(KeyEvent::ModifiersChanged(modifiers)) => {
-                self.modifiers = *modifiers;
-                false
-            }
-            Keyboard(KeyEvent::Pressed(key_code)) => self
-                .renderer
-                .window
-                .check_and_set_fullscreen(self.modifiers, *key_code),
-            _ => {
-                if let GuiEvent::Gamepad(gamepad_event) = gui_event {
-                    if let Some(event) = to_egui_event(gamepad_event) {
-                        if self.main_gui.visible() {
-                            // If the gui is visible convert gamepad events to fake input events so we can control the ui with the gamepad
-                            self.renderer.egui.state.egui_input_mut().events.push(event)
-                        } else {
-                            // If the gui is not visible pass on only the guide button
-                            if matches!(
-                                gamepad_event,
-                                GamepadEvent::ButtonDown {
-                                    button: GamepadButton::Guide,
-                                    ..
-                                } | GamepadEvent::ButtonUp {
-                                    button: GamepadButton::Guide,
-                                    ..
-                                }
-                            ) {
-                                self.renderer.egui.state.egui_input_mut().events.push(event)
-                            }
-                        }
-                    }
+        if !consumed && self.main_gui.visible() {
+            if let GuiEvent::Gamepad(gamepad_event) = gui_event {
+                if let Some(event) = to_egui_event(gamepad_event) {
+                    // If the gui is visible convert gamepad events to fake input events so we can control the ui with the gamepad
+                    self.renderer.egui.state.egui_input_mut().events.push(event)
                 }
-
-                false
             }
-        };
+        }
         if !consumed {
             self.main_gui
                 .handle_event(gui_event, audio_gui, inputs_gui, emulator_gui);
         }
     }
 
+    /// Returns true if the hotkey was handled
+    fn run_hotkey(&mut self, action: HotkeyAction) -> bool {
+        log::debug!("Hotkey: {action}");
+        match action {
+            HotkeyAction::Menu => {
+                if self.main_gui.visible() {
+                    // Let the menu handle it as a step back
+                    return false;
+                }
+                MainGui::set_main_menu_state(MainMenuState::Main);
+                // Don't let the menu see this press as a step back
+                self.renderer
+                    .egui
+                    .state
+                    .egui_input_mut()
+                    .events
+                    .retain(|e| {
+                        !matches!(
+                            e,
+                            egui::Event::Key {
+                                key: egui::Key::Escape,
+                                ..
+                            }
+                        )
+                    });
+            }
+            HotkeyAction::Fullscreen => self.renderer.window.toggle_fullscreen(),
+            HotkeyAction::Reset => {
+                let _ = self.emulator_tx.send(EmulatorCommand::Reset(false));
+            }
+            HotkeyAction::Screenshot => self.screenshot_requested = true,
+            HotkeyAction::VolumeUp => {
+                let audio_settings = &mut Settings::current_mut().audio;
+                audio_settings.volume = audio_settings.volume.saturating_add(10).min(100);
+            }
+            HotkeyAction::VolumeDown => {
+                let audio_settings = &mut Settings::current_mut().audio;
+                audio_settings.volume = audio_settings.volume.saturating_sub(10);
+            }
+            HotkeyAction::InputDisplay => {
+                let mut settings = Settings::current_mut();
+                settings.show_input_display = !settings.show_input_display;
+            }
+        }
+        true
+    }
+
     pub const MENU_TINT: Color32 = Color32::from_rgb(50, 50, 50);
 
     pub fn render(
@@ -164,10 +205,20 @@ impl MainView {
             puffin::profile_scope!("update nes texture");
 
             self.nes_texture.update(&self.renderer.queue, nes_frame);
+            if self.screenshot_requested {
+                self.screenshot_requested = false;
+                match screenshot::save(nes_frame) {
+                    Ok(path) => log::info!("Saved screenshot to {path:?}"),
+                    Err(e) => log::error!("Could not save screenshot: {:?}", e),
+                }
+            }
         }
 
         let nes_texture_id = self.nes_texture.get_id();
         let main_gui = &mut self.main_gui;
+        let applied_inputs = Settings::current()
+            .show_input_display
+            .then(|| *self.applied_inputs.read().unwrap());
         let render_result = self.renderer.render(move |ctx| {
             #[cfg(feature = "debug")]
             puffin::profile_scope!("ui");
@@ -212,6 +263,16 @@ impl MainView {
                         ui.add(nes_image);
                     });
                 });
+            if let Some(applied_inputs) = applied_inputs {
+                egui::Area::new(egui::Id::new("input_display"))
+                    .anchor(egui::Align2::LEFT_BOTTOM, [10.0, -10.0])
+                    .interactable(false)
+                    .show(ctx, |ui| {
+                        for (player, state) in applied_inputs.into_iter().enumerate() {
+                            input_display::joypad_ui(ui, &format!("P{}", player + 1), state);
+                        }
+                    });
+            }
             main_gui.ui(ctx, audio_gui, inputs_gui, emulator_gui);
         });
 
@@ -0,0 +1,51 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+
+use crate::{
+    bundle::Bundle,
+    emulation::{NESVideoFrame, NES_HEIGHT, NES_WIDTH},
+};
+
+/// Saves the frame as a BMP in the `screenshots` directory next to the settings
+pub fn save(frame: &NESVideoFrame) -> Result<PathBuf> {
+    let bundle = Bundle::current();
+    let dir = bundle.settings_path.join("screenshots");
+    fs::create_dir_all(&dir)?;
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let path = dir.join(format!("{}-{timestamp}.bmp", bundle.config.name));
+
+    let header_len: u32 = 14 + 40;
+    let data_len = NES_WIDTH * NES_HEIGHT * 4;
+
+    let mut writer = BufWriter::new(File::create(&path)?);
+    // File header
+    writer.write_all(b"BM")?;
+    writer.write_all(&(header_len + data_len).to_le_bytes())?;
+    writer.write_all(&0u32.to_le_bytes())?;
+    writer.write_all(&header_len.to_le_bytes())?;
+    // Info header, a negative height means the rows are stored top to bottom
+    writer.write_all(&40u32.to_le_bytes())?;
+    writer.write_all(&(NES_WIDTH as i32).to_le_bytes())?;
+    writer.write_all(&(-(NES_HEIGHT as i32)).to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&32u16.to_le_bytes())?;
+    writer.write_all(&0u32.to_le_bytes())?; // Uncompressed
+    writer.write_all(&data_len.to_le_bytes())?;
+    writer.write_all(&2835u32.to_le_bytes())?; // 72 DPI
+    writer.write_all(&2835u32.to_le_bytes())?;
+    writer.write_all(&0u32.to_le_bytes())?;
+    writer.write_all(&0u32.to_le_bytes())?;
+
+    // The frame is RGBA and BMP wants BGRA
+    for pixel in frame.chunks_exact(4) {
+        writer.write_all(&[pixel[2], pixel[1], pixel[0], pixel[3]])?;
+    }
+    writer.flush()?;
+    Ok(path)
+}
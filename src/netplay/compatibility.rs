@@ -0,0 +1,54 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bundle::Bundle,
+    emulation::{LocalNesState, NesRegion},
+};
+
+/// What needs to match for two players to stay in sync, on top of the netplay ROM
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Compatibility {
+    pub version: String,
+    pub emulator_config: String,
+    pub region: NesRegion,
+    pub cheats: Vec<String>,
+}
+
+impl Compatibility {
+    /// What we are running with in a session played in `region`
+    pub fn local(region: &NesRegion) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            emulator_config: LocalNesState::config_hash(region),
+            cheats: LocalNesState::cheats(region),
+            region: region.clone(),
+        }
+    }
+
+    /// Explains why we can't play with the other player
+    pub fn check(&self, remote: &Compatibility) -> Result<()> {
+        let name = &Bundle::current().config.name;
+        if self.version != remote.version {
+            bail!(
+                "The other player is running version {} of {name} and you are running version {}. Both players need the same version.",
+                remote.version,
+                self.version
+            );
+        }
+        if self.region != remote.region {
+            bail!(
+                "The other player is playing in {:?} mode and you are playing in {:?} mode.",
+                remote.region,
+                self.region
+            );
+        }
+        if self.emulator_config != remote.emulator_config {
+            bail!("The other player's emulator is set up differently. Both players need the same build of {name}.");
+        }
+        if self.cheats != remote.cheats {
+            bail!("The other player has different cheats enabled.");
+        }
+        Ok(())
+    }
+}
@@ -1,18 +1,33 @@
+use anyhow::{anyhow, Result};
 use futures::channel::oneshot::Receiver;
 use futures::{select, FutureExt};
 use futures_timer::Delay;
-use ggrs::{P2PSession, SessionBuilder, SessionState};
-use matchbox_socket::{ChannelConfig, RtcIceServerConfig, WebRtcSocket, WebRtcSocketBuilder};
-
-use serde::Deserialize;
+use ggrs::{
+    DesyncDetection, P2PSession, PlayerType, SessionBuilder, SessionState, SpectatorSession,
+};
+use matchbox_socket::{
+    ChannelConfig, PeerId, PeerState, RtcIceServerConfig, WebRtcSocket, WebRtcSocketBuilder,
+};
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::time::{Duration, Instant};
 
 use crate::bundle::Bundle;
-use crate::netplay::netplay_state::get_netplay_id;
-use crate::settings::{Settings, MAX_PLAYERS};
-
-use super::netplay_session::{GGRSConfig, NetplaySession};
+use crate::emulation::NesRegion;
+use crate::netplay::netplay_state::{get_netplay_id, get_player_name, MAX_PLAYER_NAME_LEN};
+use crate::settings::MAX_PLAYERS;
+
+use super::compatibility::Compatibility;
+use super::direct::{DirectConnection, DirectPeering};
+use super::input_delay::InputDelayNegotiation;
+use super::lobby::LobbyListing;
+use super::netplay_session::{
+    GGRSConfig, NetplaySession, DESYNC_DETECTION_INTERVAL, GGRS_CHANNEL, SIDE_CHANNEL,
+};
+use super::side_channel::{send_message, SideChannel, SideChannelMessage};
+use super::spectator_session::NetplaySpectatorSession;
 
 use super::NetplayNesState;
 
@@ -32,10 +47,12 @@ pub struct StaticNetplayServerConfiguration {
 pub enum ConnectingState {
     LoadingNetplayServerConfiguration(Connecting<LoadingNetplayServerConfiguration>),
     PeeringUp(Connecting<PeeringState>),
+    DirectPeeringUp(Connecting<DirectPeering>),
     Synchronizing(Box<Connecting<SynchonizingState>>),
 
     //TODO: Get rid of this state?
     Connected(Box<Connecting<NetplaySession>>),
+    Spectating(Box<Connecting<NetplaySpectatorSession>>),
 
     Retrying(Connecting<Retrying>),
     Failed(String),
@@ -47,6 +64,13 @@ impl ConnectingState {
     }
 
     fn start(start_method: StartMethod) -> Self {
+        if let StartMethod::Direct(_, connection) = &start_method {
+            return Self::DirectPeeringUp(Connecting {
+                state: DirectPeering::new(connection.clone()),
+                start_method,
+            });
+        }
+
         let reqwest_client = reqwest::Client::new();
         match &Bundle::current().config.netplay.server {
             NetplayServerConfiguration::Static(conf) => {
@@ -87,6 +111,7 @@ impl ConnectingState {
         match self {
             ConnectingState::LoadingNetplayServerConfiguration(loading) => loading.advance(),
             ConnectingState::PeeringUp(peering) => peering.advance(),
+            ConnectingState::DirectPeeringUp(peering) => peering.advance(),
             ConnectingState::Synchronizing(synchronizing) => synchronizing.advance(),
             ConnectingState::Retrying(retrying) => retrying.advance(),
             _ => self,
@@ -122,7 +147,19 @@ pub struct LoadingNetplayServerConfiguration {
 
 pub struct PeeringState {
     pub socket: WebRtcSocket,
+    /// If we are here to watch rather than play
+    spectator: bool,
+    /// If the connected peers are spectators
+    roles: HashMap<PeerId, bool>,
+    /// Keeps a public room listed in the lobby while waiting for a second player
+    listing: Option<LobbyListing>,
     ggrs_config: GGRSConfiguration,
+    /// Picks the input delay when we are the host
+    negotiation: Option<InputDelayNegotiation>,
+    /// The configuration the host picked
+    session_ggrs_config: Option<GGRSConfiguration>,
+    /// Messages for when the session is synchronizing, the host can get there before us
+    pending_messages: Vec<(PeerId, SideChannelMessage)>,
     unlock_url: Option<String>,
 }
 impl PeeringState {
@@ -151,6 +188,7 @@ impl PeeringState {
             StartMethod::MatchWithRandom(StartState { session_id, .. }) => {
                 format!("random_{}?next=2", session_id)
             }
+            StartMethod::Direct(..) => unreachable!("direct connections don't use rooms"),
         };
 
         let (username, password) = match &conf.matchbox.ice.credentials {
@@ -175,6 +213,7 @@ impl PeeringState {
             WebRtcSocketBuilder::new(room_url)
                 .ice_server(ice_server)
                 .add_channel(ChannelConfig::unreliable())
+                .add_channel(ChannelConfig::reliable())
                 .build()
         };
 
@@ -197,25 +236,155 @@ impl PeeringState {
 
         Self {
             socket,
+            spectator: start_method.is_spectator(),
+            roles: HashMap::new(),
+            listing: match &start_method {
+                StartMethod::Start(_, room_name, JoinOrHost::HostPublic) => {
+                    LobbyListing::new(room_name)
+                }
+                _ => None,
+            },
             ggrs_config: conf.ggrs.clone(),
+            negotiation: None,
+            session_ggrs_config: None,
+            pending_messages: Vec::new(),
             unlock_url: maybe_unlock_url,
         }
     }
 }
 
+pub enum GgrsSession {
+    Player(P2PSession<GGRSConfig>),
+    Spectator(SpectatorSession<GGRSConfig>),
+}
+
+impl GgrsSession {
+    fn poll_remote_clients(&mut self) {
+        match self {
+            GgrsSession::Player(session) => session.poll_remote_clients(),
+            GgrsSession::Spectator(session) => session.poll_remote_clients(),
+        }
+    }
+
+    fn current_state(&self) -> SessionState {
+        match self {
+            GgrsSession::Player(session) => session.current_state(),
+            GgrsSession::Spectator(session) => session.current_state(),
+        }
+    }
+}
+
 pub struct SynchonizingState {
-    p2p_session: P2PSession<GGRSConfig>,
+    session: GgrsSession,
+    /// The peers watching the game
+    spectators: Vec<PeerId>,
+    /// The other players that haven't told us what they are running yet
+    unchecked_players: Vec<PeerId>,
+    side_channel: SideChannel,
+    /// Messages that arrived before we got here
+    pending_messages: Vec<(PeerId, SideChannelMessage)>,
+    /// The names of the players, by player handle
+    player_names: [Option<String>; MAX_PLAYERS],
+    input_delay: usize,
+    /// Guests start from the game state of the host
+    waiting_for_initial_state: bool,
+    /// The players need to run the same build of the game
+    compatibility: Compatibility,
     pub unlock_url: Option<String>,
     pub start_time: Instant,
 }
 impl SynchonizingState {
-    pub fn new(p2p_session: P2PSession<GGRSConfig>, unlock_url: Option<String>) -> Self {
+    pub fn new(
+        session: GgrsSession,
+        remote_players: Vec<PeerId>,
+        spectators: Vec<PeerId>,
+        mut side_channel: SideChannel,
+        unlock_url: Option<String>,
+        input_delay: usize,
+        start_method: &StartMethod,
+        pending_messages: Vec<(PeerId, SideChannelMessage)>,
+    ) -> Self {
+        let compatibility = Compatibility::local(start_method.region());
+        side_channel.send_to_all(&SideChannelMessage::Compatibility(compatibility.clone()));
+
+        let mut player_names: [Option<String>; MAX_PLAYERS] = Default::default();
+        let mut waiting_for_initial_state = false;
+        if let GgrsSession::Player(p2p_session) = &session {
+            let name = get_player_name();
+            let local_player_handles = p2p_session.local_player_handles();
+            for handle in &local_player_handles {
+                side_channel.send_to_all(&SideChannelMessage::PlayerName {
+                    handle: *handle,
+                    name: name.clone(),
+                });
+                player_names[*handle] = Some(name.clone());
+            }
+
+            if local_player_handles.contains(&0) {
+                // Booting the ROM on each machine is not guaranteed to end up in the same state
+                match start_method.start_state().game_state.save_state() {
+                    Ok(state) => {
+                        let message = SideChannelMessage::InitialState { state };
+                        for player in &remote_players {
+                            side_channel.send(*player, &message);
+                        }
+                    }
+                    Err(e) => log::error!("Could not save the initial game state: {:?}", e),
+                }
+            } else {
+                waiting_for_initial_state = true;
+            }
+        }
         SynchonizingState {
-            p2p_session,
+            session,
+            spectators,
+            unchecked_players: remote_players,
+            side_channel,
+            pending_messages,
+            player_names,
+            input_delay,
+            waiting_for_initial_state,
+            compatibility,
             unlock_url,
             start_time: Instant::now(),
         }
     }
+
+    fn receive(&mut self, start_state: &mut StartState) -> Result<()> {
+        let mut messages = std::mem::take(&mut self.pending_messages);
+        messages.extend(self.side_channel.receive());
+        for (peer, message) in messages {
+            match message {
+                SideChannelMessage::Compatibility(remote) => {
+                    let check = self.compatibility.check(&remote);
+                    if self.spectators.contains(&peer) {
+                        // A spectator notices by itself and leaves, only the players need to match
+                        if let Err(e) = check {
+                            log::warn!("Spectator {} can't watch: {:?}", peer, e);
+                        }
+                    } else if self.unchecked_players.contains(&peer) {
+                        check?;
+                        self.unchecked_players.retain(|player| *player != peer);
+                    }
+                }
+                SideChannelMessage::PlayerName { handle, name } if handle < MAX_PLAYERS => {
+                    log::debug!("Player {} is called {}", handle + 1, name);
+                    self.player_names[handle] =
+                        Some(name.chars().take(MAX_PLAYER_NAME_LEN).collect());
+                }
+                SideChannelMessage::InitialState { state } if self.waiting_for_initial_state => {
+                    start_state
+                        .game_state
+                        .load_state(&state)
+                        .map_err(|e| anyhow!("Could not load the game state of the host: {e}"))?;
+                    log::debug!("Starting from the game state of the host");
+                    self.waiting_for_initial_state = false;
+                }
+                message => log::warn!("Unexpected message from {:?}: {:?}", peer, message),
+            }
+        }
+        Ok(())
+    }
 }
 type RoomName = String;
 
@@ -223,6 +392,9 @@ type RoomName = String;
 pub enum JoinOrHost {
     Join,
     Host,
+    /// Host and list the game in the lobby
+    HostPublic,
+    Watch,
 }
 
 #[derive(Clone, Debug)]
@@ -230,6 +402,36 @@ pub enum StartMethod {
     Start(StartState, RoomName, JoinOrHost),
     Resume(StartState),
     MatchWithRandom(StartState),
+    Direct(StartState, DirectConnection),
+}
+
+impl StartMethod {
+    pub fn is_spectator(&self) -> bool {
+        matches!(self, StartMethod::Start(.., JoinOrHost::Watch))
+    }
+
+    /// The region the game of the session runs in
+    pub fn region(&self) -> &NesRegion {
+        self.start_state().game_state.region()
+    }
+
+    fn start_state(&self) -> &StartState {
+        match self {
+            StartMethod::Start(start_state, ..)
+            | StartMethod::Resume(start_state)
+            | StartMethod::MatchWithRandom(start_state)
+            | StartMethod::Direct(start_state, _) => start_state,
+        }
+    }
+
+    fn start_state_mut(&mut self) -> &mut StartState {
+        match self {
+            StartMethod::Start(start_state, ..)
+            | StartMethod::Resume(start_state)
+            | StartMethod::MatchWithRandom(start_state)
+            | StartMethod::Direct(start_state, _) => start_state,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -282,41 +484,171 @@ impl Connecting<PeeringState> {
     }
 
     fn advance(mut self) -> ConnectingState {
+        if let Some(listing) = &mut self.state.listing {
+            listing.heartbeat();
+        }
+
+        let spectator = self.state.spectator;
         let socket = &mut self.state.socket;
-        socket.update_peers();
+        for (peer, state) in socket.update_peers() {
+            match state {
+                PeerState::Connected => send_message(
+                    socket.channel_mut(SIDE_CHANNEL),
+                    peer,
+                    &SideChannelMessage::Role { spectator },
+                ),
+                PeerState::Disconnected => {
+                    self.state.roles.remove(&peer);
+                }
+            }
+        }
+
+        for (peer, packet) in socket.channel_mut(SIDE_CHANNEL).receive() {
+            match bincode::deserialize(&packet) {
+                Ok(SideChannelMessage::Role { spectator }) => {
+                    self.state.roles.insert(peer, spectator);
+                }
+                Ok(SideChannelMessage::AlreadyStarted) => {
+                    return ConnectingState::Failed(
+                        "The game has already started. To watch a game, join it before the second player does."
+                            .to_string(),
+                    );
+                }
+                Ok(SideChannelMessage::Ping { id }) => send_message(
+                    socket.channel_mut(SIDE_CHANNEL),
+                    peer,
+                    &SideChannelMessage::Pong { id },
+                ),
+                Ok(SideChannelMessage::Pong { id }) => {
+                    if let Some(negotiation) = &mut self.state.negotiation {
+                        negotiation.pong(id);
+                    }
+                }
+                Ok(SideChannelMessage::SessionSettings(ggrs_config)) => {
+                    self.state.session_ggrs_config = Some(ggrs_config);
+                }
+                Ok(message) => self.state.pending_messages.push((peer, message)),
+                Err(e) => log::warn!("Could not read message from {:?}: {:?}", peer, e),
+            }
+        }
+
+        let connected_peers: Vec<PeerId> = socket.connected_peers().collect();
+        if connected_peers
+            .iter()
+            .any(|peer| !self.state.roles.contains_key(peer))
+        {
+            // Wait until everyone has told us if they are playing or watching
+            return ConnectingState::PeeringUp(self);
+        }
+        let (spectators, mut players): (Vec<PeerId>, Vec<PeerId>) = connected_peers
+            .into_iter()
+            .partition(|peer| self.state.roles[peer]);
 
-        let connected_peers = socket.connected_peers().count();
-        if connected_peers >= MAX_PLAYERS {
+        if !spectator && players.len() >= MAX_PLAYERS {
             return ConnectingState::Failed("Room is full".to_string());
         }
 
-        let remaining = MAX_PLAYERS - (connected_peers + 1);
+        let remaining = MAX_PLAYERS.saturating_sub(players.len() + usize::from(!spectator));
         if remaining == 0 {
+            let ggrs_config = if spectator {
+                self.state.ggrs_config.clone()
+            } else if players.iter().all(|player| Some(*player) > socket.id()) {
+                // We are the host, pick the configuration and tell the other player
+                let negotiation = self.state.negotiation.get_or_insert_with(|| {
+                    InputDelayNegotiation::new(self.state.ggrs_config.clone())
+                });
+                let (message, ggrs_config) = negotiation.advance();
+                let Some(ggrs_config) = ggrs_config else {
+                    if let Some(message) = message {
+                        for player in &players {
+                            send_message(socket.channel_mut(SIDE_CHANNEL), *player, &message);
+                        }
+                    }
+                    return ConnectingState::PeeringUp(self);
+                };
+                for player in &players {
+                    send_message(
+                        socket.channel_mut(SIDE_CHANNEL),
+                        *player,
+                        &SideChannelMessage::SessionSettings(ggrs_config.clone()),
+                    );
+                }
+                ggrs_config
+            } else if let Some(ggrs_config) = self.state.session_ggrs_config.clone() {
+                ggrs_config
+            } else {
+                // Waiting for the host to pick the configuration
+                return ConnectingState::PeeringUp(self);
+            };
+
             log::debug!("Got all players! Synchonizing...");
-            let players = socket.players();
-            let ggrs_config = self.state.ggrs_config.clone();
-            let mut sess_build = SessionBuilder::<GGRSConfig>::new()
-                .with_num_players(MAX_PLAYERS)
-                .with_input_delay(ggrs_config.input_delay)
-                .with_fps(Settings::current_mut().get_nes_region().to_fps() as usize)
-                .unwrap()
-                .with_max_prediction_window(ggrs_config.max_prediction)
-                .expect("ggrs session to configure");
-
-            for (i, player) in players.into_iter().enumerate() {
-                sess_build = sess_build
-                    .add_player(player, i)
-                    .expect("player to be added to ggrs session");
-            }
+            let sess_build = session_builder(&ggrs_config, self.start_method.region());
+
+            let ggrs_channel = socket
+                .take_channel(GGRS_CHANNEL)
+                .expect("ggrs channel to be available");
+            let side_channel = socket
+                .take_channel(SIDE_CHANNEL)
+                .expect("side channel to be available");
+
+            // Everyone agrees on the player handles by sorting the peer ids
+            players.sort();
+            let remote_players = players.clone();
+            let session = if spectator {
+                // The host (player 1) is the one sending the inputs to the spectators
+                let host = players[0];
+                log::debug!("Watching the game of {:?}", host);
+                GgrsSession::Spectator(sess_build.start_spectator_session(host, ggrs_channel))
+            } else {
+                let local_id = socket.id().expect("a peer id when connected to peers");
+                players.push(local_id);
+                players.sort();
+
+                let mut sess_build = sess_build;
+                for (i, player) in players.iter().enumerate() {
+                    sess_build = sess_build
+                        .add_player(
+                            if *player == local_id {
+                                PlayerType::Local
+                            } else {
+                                PlayerType::Remote(*player)
+                            },
+                            i,
+                        )
+                        .expect("player to be added to ggrs session");
+                }
 
-            ConnectingState::Synchronizing(Box::new(Connecting {
-                start_method: self.start_method,
-                state: SynchonizingState::new(
+                // The host sends the inputs to the spectators
+                if players[0] == local_id {
+                    for (i, spectator) in spectators.iter().enumerate() {
+                        sess_build = sess_build
+                            .add_player(PlayerType::Spectator(*spectator), MAX_PLAYERS + i)
+                            .expect("spectator to be added to ggrs session");
+                    }
+                }
+                GgrsSession::Player(
                     sess_build
-                        .start_p2p_session(self.state.socket)
+                        .start_p2p_session(ggrs_channel)
                         .expect("ggrs session to start"),
-                    self.state.unlock_url.clone(),
-                ),
+                )
+            };
+
+            let state = SynchonizingState::new(
+                session,
+                remote_players,
+                spectators,
+                SideChannel::WebRtc {
+                    socket: self.state.socket,
+                    channel: side_channel,
+                },
+                self.state.unlock_url.clone(),
+                ggrs_config.input_delay,
+                &self.start_method,
+                self.state.pending_messages,
+            );
+            ConnectingState::Synchronizing(Box::new(Connecting {
+                start_method: self.start_method,
+                state,
             }))
         } else {
             ConnectingState::PeeringUp(self)
@@ -326,14 +658,43 @@ impl Connecting<PeeringState> {
 
 impl Connecting<SynchonizingState> {
     fn advance(mut self) -> ConnectingState {
-        self.state.p2p_session.poll_remote_clients();
-        if let SessionState::Running = self.state.p2p_session.current_state() {
+        self.state.session.poll_remote_clients();
+        if let Err(e) = self.state.receive(self.start_method.start_state_mut()) {
+            log::error!("Could not start the session: {:?}", e);
+            return ConnectingState::Failed(e.to_string());
+        }
+        if let (SessionState::Running, false, true) = (
+            self.state.session.current_state(),
+            self.state.waiting_for_initial_state,
+            self.state.unchecked_players.is_empty(),
+        ) {
             let start_method = self.start_method;
             log::debug!("Synchronized!");
-            ConnectingState::Connected(Box::new(Connecting {
-                start_method: start_method.clone(),
-                state: NetplaySession::new(start_method.clone(), self.state.p2p_session),
-            }))
+            match self.state.session {
+                GgrsSession::Player(p2p_session) => {
+                    ConnectingState::Connected(Box::new(Connecting {
+                        start_method: start_method.clone(),
+                        state: NetplaySession::new(
+                            start_method.clone(),
+                            p2p_session,
+                            self.state.side_channel,
+                            self.state.spectators,
+                            self.state.player_names,
+                            self.state.input_delay,
+                        ),
+                    }))
+                }
+                GgrsSession::Spectator(spectator_session) => {
+                    ConnectingState::Spectating(Box::new(Connecting {
+                        start_method: start_method.clone(),
+                        state: NetplaySpectatorSession::new(
+                            start_method,
+                            spectator_session,
+                            self.state.side_channel,
+                        ),
+                    }))
+                }
+            }
         } else {
             ConnectingState::Synchronizing(Box::new(self))
         }
@@ -380,10 +741,7 @@ impl Connecting<Retrying> {
                                             credentials: IceCredentials::None,
                                         },
                                     },
-                                    ggrs: GGRSConfiguration {
-                                        max_prediction: 12,
-                                        input_delay: 2,
-                                    },
+                                    ggrs: GGRSConfiguration::default(),
                                 }),
                                 self.start_method.clone(),
                             ),
@@ -432,12 +790,37 @@ pub struct MatchboxConfiguration {
     ice: IceConfiguration,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct GGRSConfiguration {
     pub max_prediction: usize,
     pub input_delay: usize,
 }
 
+impl Default for GGRSConfiguration {
+    fn default() -> Self {
+        Self {
+            max_prediction: 12,
+            input_delay: 2,
+        }
+    }
+}
+
+pub fn session_builder(
+    ggrs_config: &GGRSConfiguration,
+    region: &NesRegion,
+) -> SessionBuilder<GGRSConfig> {
+    SessionBuilder::<GGRSConfig>::new()
+        .with_num_players(MAX_PLAYERS)
+        .with_input_delay(ggrs_config.input_delay)
+        .with_fps(region.to_fps() as usize)
+        .unwrap()
+        .with_max_prediction_window(ggrs_config.max_prediction)
+        .expect("ggrs session to configure")
+        .with_desync_detection_mode(DesyncDetection::On {
+            interval: DESYNC_DETECTION_INTERVAL,
+        })
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct IceConfiguration {
     urls: Vec<String>,
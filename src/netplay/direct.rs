@@ -0,0 +1,513 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use futures::channel::oneshot::{self, Receiver};
+use ggrs::{Message, NonBlockingSocket, PlayerType};
+use matchbox_socket::{PeerId, PeerState};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::bundle::Bundle;
+
+use super::{
+    connecting_state::{
+        session_builder, Connecting, ConnectingState, GGRSConfiguration, GgrsSession,
+        SynchonizingState,
+    },
+    input_delay::InputDelayNegotiation,
+    rom_hash,
+    side_channel::{self, SideChannel, SideChannelMessage},
+};
+
+pub const DEFAULT_PORT: u16 = 7870;
+/// Hosts announce their games on the local network on this port
+const DISCOVERY_PORT: u16 = 7871;
+const BEACON_INTERVAL: Duration = Duration::from_secs(1);
+/// Games that haven't been announced for this long are gone
+const BEACON_TIMEOUT: Duration = Duration::from_secs(3);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const CONNECT_RETRY_COOLDOWN: Duration = Duration::from_secs(1);
+/// Large enough for any GGRS message
+const RECV_BUFFER_SIZE: usize = 4096;
+/// The largest side channel packet accepted, the game states are the largest messages by far
+const MAX_SIDE_CHANNEL_PACKET_SIZE: usize = 16 * 1024 * 1024;
+
+/// Playing without a signalling server, one player hosts on a port and the other connects to it
+#[derive(Clone, Debug)]
+pub enum DirectConnection {
+    Host(u16),
+    Join(SocketAddr),
+}
+
+/// GGRS identifies peers by `PeerId`, so make one up from the address
+fn peer_id(addr: SocketAddr) -> PeerId {
+    PeerId(Uuid::from_u128(u128::from_le_bytes(
+        md5::compute(addr.to_string()).0,
+    )))
+}
+
+/// GGRS's UDP socket, addressed with the `PeerId`s the rest of netplay uses
+pub struct DirectSocket {
+    udp_socket: UdpSocket,
+    peers: HashMap<PeerId, SocketAddr>,
+    buffer: Vec<u8>,
+}
+
+impl NonBlockingSocket<PeerId> for DirectSocket {
+    fn send_to(&mut self, msg: &Message, addr: &PeerId) {
+        let Some(addr) = self.peers.get(addr) else {
+            return;
+        };
+        match bincode::serialize(msg) {
+            Ok(packet) => {
+                if let Err(e) = self.udp_socket.send_to(&packet, addr) {
+                    log::trace!("Could not send to {:?}: {:?}", addr, e);
+                }
+            }
+            Err(e) => log::error!("Could not serialize GGRS message: {:?}", e),
+        }
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(PeerId, Message)> {
+        let mut messages = Vec::new();
+        loop {
+            match self.udp_socket.recv_from(&mut self.buffer) {
+                Ok((len, addr)) => {
+                    let Some(peer) = self
+                        .peers
+                        .iter()
+                        .find(|(_, peer_addr)| **peer_addr == addr)
+                        .map(|(peer, _)| *peer)
+                    else {
+                        continue;
+                    };
+                    match bincode::deserialize(&self.buffer[..len]) {
+                        Ok(msg) => messages.push((peer, msg)),
+                        Err(e) => log::warn!("Invalid GGRS message from {:?}: {:?}", addr, e),
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // Windows reports when an earlier packet couldn't be delivered
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => {
+                    log::warn!("Could not receive GGRS messages: {:?}", e);
+                    break;
+                }
+            }
+        }
+        messages
+    }
+}
+
+/// A side channel to the other player over TCP, every packet is prefixed with its length
+pub struct TcpSideChannel {
+    peer: PeerId,
+    stream: Option<TcpStream>,
+    read_buffer: Vec<u8>,
+    disconnect_reported: bool,
+}
+
+impl TcpSideChannel {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            peer: peer_id(stream.peer_addr()?),
+            stream: Some(stream),
+            read_buffer: Vec::new(),
+            disconnect_reported: false,
+        })
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.stream
+            .as_ref()
+            .and_then(|stream| stream.peer_addr().ok())
+            .map(|addr| addr.ip())
+    }
+
+    pub fn update_peers(&mut self) -> Vec<(PeerId, PeerState)> {
+        // The peer is already connected when the channel is created
+        if self.stream.is_none() && !self.disconnect_reported {
+            self.disconnect_reported = true;
+            vec![(self.peer, PeerState::Disconnected)]
+        } else {
+            vec![]
+        }
+    }
+
+    pub fn connected_peers(&self) -> Vec<PeerId> {
+        self.stream.iter().map(|_| self.peer).collect()
+    }
+
+    pub fn send(&mut self, message: &SideChannelMessage) {
+        let Some(stream) = &mut self.stream else {
+            return;
+        };
+        let result = bincode::serialize(message)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            .and_then(|packet| {
+                // Block while writing so a large packet isn't cut short
+                stream.set_nonblocking(false)?;
+                stream.write_all(&(packet.len() as u32).to_le_bytes())?;
+                stream.write_all(&packet)?;
+                stream.set_nonblocking(true)
+            });
+        if let Err(e) = result {
+            log::warn!("Lost the side channel to {:?}: {:?}", self.peer, e);
+            self.stream = None;
+        }
+    }
+
+    pub fn receive(&mut self) -> Vec<(PeerId, Box<[u8]>)> {
+        if let Some(stream) = &mut self.stream {
+            let mut buf = [0; 4096];
+            loop {
+                match stream.read(&mut buf) {
+                    Ok(0) => {
+                        log::debug!("Side channel to {:?} closed", self.peer);
+                        self.stream = None;
+                        break;
+                    }
+                    Ok(len) => self.read_buffer.extend_from_slice(&buf[..len]),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        log::warn!("Lost the side channel to {:?}: {:?}", self.peer, e);
+                        self.stream = None;
+                        break;
+                    }
+                }
+            }
+        }
+
+        let mut packets = vec![];
+        while self.read_buffer.len() >= 4 {
+            let len =
+                u32::from_le_bytes(self.read_buffer[..4].try_into().expect("4 bytes")) as usize;
+            if len > MAX_SIDE_CHANNEL_PACKET_SIZE {
+                log::warn!(
+                    "Dropping the side channel to {:?}, it sent a packet of {len} bytes",
+                    self.peer
+                );
+                self.stream = None;
+                self.read_buffer.clear();
+                break;
+            }
+            let end = 4 + len;
+            if self.read_buffer.len() < end {
+                break;
+            }
+            packets.push((self.peer, self.read_buffer[4..end].into()));
+            self.read_buffer.drain(..end);
+        }
+        packets
+    }
+}
+
+/// What a host broadcasts on the local network
+#[derive(Serialize, Deserialize, Debug)]
+struct LanBeacon {
+    name: String,
+    rom_hash: String,
+    port: u16,
+}
+
+#[derive(Clone, Debug)]
+pub struct LanGame {
+    pub name: String,
+    pub addr: SocketAddr,
+}
+
+/// Listens for games hosted on the local network
+pub struct LanDiscovery {
+    socket: UdpSocket,
+    games: HashMap<SocketAddr, (LanGame, Instant)>,
+}
+
+impl LanDiscovery {
+    pub fn new() -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            games: HashMap::new(),
+        })
+    }
+
+    /// The games that are currently announced
+    pub fn poll(&mut self) -> Vec<LanGame> {
+        let mut buf = [0; 1024];
+        while let Ok((len, from)) = self.socket.recv_from(&mut buf) {
+            match bincode::deserialize::<LanBeacon>(&buf[..len]) {
+                Ok(beacon) if beacon.rom_hash == rom_hash() => {
+                    let addr = SocketAddr::new(from.ip(), beacon.port);
+                    let game = LanGame {
+                        name: beacon.name,
+                        addr,
+                    };
+                    self.games.insert(addr, (game, Instant::now()));
+                }
+                // Some other game
+                Ok(_) => {}
+                Err(e) => log::trace!("Unknown beacon from {:?}: {:?}", from, e),
+            }
+        }
+        self.games
+            .retain(|_, (_, last_seen)| last_seen.elapsed() < BEACON_TIMEOUT);
+
+        let mut games: Vec<LanGame> = self.games.values().map(|(game, _)| game.clone()).collect();
+        games.sort_by_key(|game| game.addr);
+        games
+    }
+}
+
+pub struct DirectPeering {
+    pub connection: DirectConnection,
+    listener: Option<TcpListener>,
+    beacon: Option<(UdpSocket, Instant)>,
+    connecting: Option<Receiver<io::Result<TcpStream>>>,
+    next_attempt: Instant,
+    side_channel: Option<TcpSideChannel>,
+    /// The socket GGRS uses, bound once the other player has connected
+    udp_socket: Option<UdpSocket>,
+    /// The GGRS address of the other player
+    remote_addr: Option<SocketAddr>,
+    /// Picks the input delay when we are the host
+    negotiation: Option<InputDelayNegotiation>,
+    /// The configuration the host picked
+    ggrs_config: Option<GGRSConfiguration>,
+    /// Messages for when the session is synchronizing, the host can get there before us
+    pending_messages: Vec<(PeerId, SideChannelMessage)>,
+}
+
+impl DirectPeering {
+    pub fn new(connection: DirectConnection) -> Self {
+        Self {
+            connection,
+            listener: None,
+            beacon: None,
+            connecting: None,
+            next_attempt: Instant::now(),
+            side_channel: None,
+            udp_socket: None,
+            remote_addr: None,
+            negotiation: None,
+            ggrs_config: None,
+            pending_messages: Vec::new(),
+        }
+    }
+
+    fn host(&mut self, port: u16) -> io::Result<()> {
+        if self.listener.is_none() {
+            log::debug!("Hosting a direct game on port {}", port);
+            let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))?;
+            listener.set_nonblocking(true)?;
+            self.listener = Some(listener);
+        }
+
+        let Some(listener) = &self.listener else {
+            return Ok(());
+        };
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                log::debug!("{:?} connected", addr);
+                self.side_channel = Some(TcpSideChannel::new(stream)?);
+                self.listener = None;
+                self.beacon = None;
+                self.hello(port)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.announce(port),
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    /// Lets the players on the local network find the game
+    fn announce(&mut self, port: u16) {
+        if self.beacon.is_none() {
+            match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+                .and_then(|socket| socket.set_broadcast(true).map(|_| socket))
+            {
+                Ok(socket) => self.beacon = Some((socket, Instant::now())),
+                Err(e) => {
+                    log::warn!("Can't announce the game on the local network: {:?}", e);
+                    return;
+                }
+            }
+        }
+
+        let Some((socket, next_beacon)) = &mut self.beacon else {
+            return;
+        };
+        if Instant::now() >= *next_beacon {
+            *next_beacon = Instant::now() + BEACON_INTERVAL;
+            let beacon = LanBeacon {
+                name: Bundle::current().config.name.clone(),
+                rom_hash: rom_hash(),
+                port,
+            };
+            if let Ok(packet) = bincode::serialize(&beacon) {
+                let _ = socket.send_to(&packet, (Ipv4Addr::BROADCAST, DISCOVERY_PORT));
+            }
+        }
+    }
+
+    fn join(&mut self, addr: SocketAddr) -> io::Result<()> {
+        if let Some(result) = &mut self.connecting {
+            match result.try_recv() {
+                Ok(Some(Ok(stream))) => {
+                    log::debug!("Connected to {:?}", addr);
+                    self.connecting = None;
+                    self.side_channel = Some(TcpSideChannel::new(stream)?);
+                    self.hello(0)?;
+                }
+                Ok(None) => {} // Still connecting
+                Ok(Some(Err(e))) => {
+                    // The host might not be up yet, keep trying
+                    log::debug!("Could not connect to {:?}: {:?}", addr, e);
+                    self.connecting = None;
+                    self.next_attempt = Instant::now() + CONNECT_RETRY_COOLDOWN;
+                }
+                Err(e) => {
+                    log::warn!("Connection attempt to {:?} was lost: {:?}", addr, e);
+                    self.connecting = None;
+                }
+            }
+        } else if Instant::now() >= self.next_attempt {
+            log::debug!("Connecting to {:?}", addr);
+            let (sender, result) = oneshot::channel();
+            std::thread::spawn(move || {
+                let _ = sender.send(TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT));
+            });
+            self.connecting = Some(result);
+        }
+        Ok(())
+    }
+
+    /// Tells the other player which UDP port GGRS is on
+    fn hello(&mut self, port: u16) -> io::Result<()> {
+        // Binding to 0 lets the OS pick a free port. The socket is kept for GGRS so no one else can take the port
+        let udp_socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        udp_socket.set_nonblocking(true)?;
+        let port = udp_socket.local_addr()?.port();
+        self.udp_socket = Some(udp_socket);
+        if let Some(side_channel) = &mut self.side_channel {
+            side_channel.send(&SideChannelMessage::DirectHello { udp_port: port });
+        }
+        Ok(())
+    }
+
+    /// The GGRS address of the other player and the configuration to use, once known
+    fn poll(&mut self) -> io::Result<Option<(SocketAddr, GGRSConfiguration)>> {
+        let Some(side_channel) = &mut self.side_channel else {
+            match self.connection.clone() {
+                DirectConnection::Host(port) => self.host(port)?,
+                DirectConnection::Join(addr) => self.join(addr)?,
+            }
+            return Ok(None);
+        };
+
+        for (peer, message) in side_channel::decode(side_channel.receive()) {
+            match message {
+                SideChannelMessage::DirectHello { udp_port } => {
+                    self.remote_addr = side_channel
+                        .peer_ip()
+                        .map(|ip| SocketAddr::new(ip, udp_port));
+                }
+                SideChannelMessage::Ping { id } => {
+                    side_channel.send(&SideChannelMessage::Pong { id });
+                }
+                SideChannelMessage::Pong { id } => {
+                    if let Some(negotiation) = &mut self.negotiation {
+                        negotiation.pong(id);
+                    }
+                }
+                SideChannelMessage::SessionSettings(ggrs_config) => {
+                    self.ggrs_config = Some(ggrs_config);
+                }
+                message => self.pending_messages.push((peer, message)),
+            }
+        }
+        if side_channel.connected_peers().is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "The other player left",
+            ));
+        }
+
+        // The host picks the configuration
+        if matches!(self.connection, DirectConnection::Host(_)) && self.ggrs_config.is_none() {
+            let negotiation = self
+                .negotiation
+                .get_or_insert_with(|| InputDelayNegotiation::new(GGRSConfiguration::default()));
+            let (message, ggrs_config) = negotiation.advance();
+            if let Some(message) = message {
+                side_channel.send(&message);
+            }
+            if let Some(ggrs_config) = &ggrs_config {
+                side_channel.send(&SideChannelMessage::SessionSettings(ggrs_config.clone()));
+            }
+            self.ggrs_config = ggrs_config;
+        }
+        Ok(self.remote_addr.zip(self.ggrs_config.clone()))
+    }
+}
+
+impl Connecting<DirectPeering> {
+    pub fn advance(mut self) -> ConnectingState {
+        match self.state.poll() {
+            Ok(Some((remote_addr, ggrs_config))) => {
+                log::debug!("Got the other player at {:?}! Synchonizing...", remote_addr);
+                let (Some(side_channel), Some(udp_socket)) =
+                    (self.state.side_channel.take(), self.state.udp_socket.take())
+                else {
+                    return ConnectingState::Failed("Connection was not set up".to_string());
+                };
+                let remote = peer_id(remote_addr);
+                let socket = DirectSocket {
+                    udp_socket,
+                    peers: HashMap::from([(remote, remote_addr)]),
+                    buffer: vec![0; RECV_BUFFER_SIZE],
+                };
+
+                // The host is player 1
+                let local_handle = match self.state.connection {
+                    DirectConnection::Host(_) => 0,
+                    DirectConnection::Join(_) => 1,
+                };
+                let p2p_session = session_builder(&ggrs_config, self.start_method.region())
+                    .add_player(PlayerType::Local, local_handle)
+                    .and_then(|builder| {
+                        builder.add_player(PlayerType::Remote(remote), 1 - local_handle)
+                    })
+                    .expect("players to be added to ggrs session")
+                    .start_p2p_session(socket)
+                    .expect("ggrs session to start");
+
+                let state = SynchonizingState::new(
+                    GgrsSession::Player(p2p_session),
+                    vec![remote],
+                    vec![],
+                    SideChannel::Tcp(side_channel),
+                    None,
+                    ggrs_config.input_delay,
+                    &self.start_method,
+                    std::mem::take(&mut self.state.pending_messages),
+                );
+                ConnectingState::Synchronizing(Box::new(Connecting {
+                    start_method: self.start_method,
+                    state,
+                }))
+            }
+            Ok(None) => ConnectingState::DirectPeeringUp(self),
+            Err(e) => {
+                log::error!("Direct connection failed: {:?}", e);
+                ConnectingState::Failed(format!("Could not connect: {e}"))
+            }
+        }
+    }
+}
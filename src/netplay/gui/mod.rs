@@ -1,6 +1,10 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use egui::{Align, Button, Color32, FontId, Label, RichText, TextEdit, Ui, Widget};
+use futures::channel::oneshot::Receiver;
 use serde::Deserialize;
 
 use crate::{
@@ -8,12 +12,21 @@ use crate::{
     emulation::LocalNesState,
     gui::{esc_pressed, MenuButton},
     main_view::gui::{MainGui, MainMenuState},
-    netplay::{connecting_state::StartMethod, netplay_state::MAX_ROOM_NAME_LEN},
+    netplay::{
+        connecting_state::StartMethod,
+        netplay_state::{get_player_name, MAX_PLAYER_NAME_LEN, MAX_ROOM_NAME_LEN},
+    },
+    settings::{NetplayInputDelay, Settings, MAX_PLAYERS},
 };
 
 use super::{
     connecting_state::{Connecting, SynchonizingState},
+    direct::{DirectConnection, LanDiscovery, DEFAULT_PORT},
+    input_delay::MAX_INPUT_DELAY,
+    lobby::{self, LobbyProvider, LobbyRoom, LOBBY_REGIONS},
+    netplay_session::{ConnectionQuality, ConnectionStats},
     netplay_state::{Connected, Netplay, NetplayState},
+    replay::{self, ReplayPlayback},
     ConnectingState, NetplayStateHandler,
 };
 #[cfg(feature = "debug")]
@@ -33,8 +46,14 @@ impl Default for NetplayVoca {
 
 pub struct NetplayGui {
     #[cfg(feature = "debug")]
-    pub stats: [debug::NetplayStats; crate::settings::MAX_PLAYERS],
+    pub stats: [debug::NetplayStats; MAX_PLAYERS],
     room_name: Option<String>,
+    /// If the entered code is for watching rather than joining
+    watch: bool,
+    direct: Option<DirectGui>,
+    lobby: Option<LobbyGui>,
+    replays: Option<ReplaysGui>,
+    chat_input: String,
     last_screen: Option<&'static str>,
 }
 
@@ -44,11 +63,124 @@ impl NetplayGui {
             #[cfg(feature = "debug")]
             stats: [debug::NetplayStats::new(), debug::NetplayStats::new()],
             room_name: None,
+            watch: false,
+            direct: None,
+            lobby: None,
+            replays: None,
+            chat_input: String::new(),
             last_screen: None,
         }
     }
 }
 
+struct DirectGui {
+    port: String,
+    address: String,
+    error: Option<String>,
+    discovery: Option<LanDiscovery>,
+}
+
+impl DirectGui {
+    fn new() -> Self {
+        Self {
+            port: DEFAULT_PORT.to_string(),
+            address: String::new(),
+            error: None,
+            discovery: LanDiscovery::new()
+                .map_err(|e| log::warn!("Can't look for games on the local network: {:?}", e))
+                .ok(),
+        }
+    }
+}
+
+/// How long chat messages stay on top of the game
+const CHAT_MESSAGE_DURATION: Duration = Duration::from_secs(8);
+const MAX_CHAT_MESSAGE_LEN: usize = 100;
+
+/// How often the list of open rooms is refreshed
+const LOBBY_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+struct LobbyGui {
+    provider: Arc<dyn LobbyProvider>,
+    /// The rooms being fetched and when the request was sent
+    request: Option<(Receiver<anyhow::Result<Vec<LobbyRoom>>>, Instant)>,
+    rooms: Vec<LobbyRoom>,
+    /// The round trip time of the last listing
+    latency: Duration,
+    error: Option<String>,
+    next_refresh: Instant,
+}
+
+impl LobbyGui {
+    fn new(provider: Arc<dyn LobbyProvider>) -> Self {
+        Self {
+            provider,
+            request: None,
+            rooms: Vec::new(),
+            latency: Duration::ZERO,
+            error: None,
+            next_refresh: Instant::now(),
+        }
+    }
+
+    fn poll(&mut self) {
+        if let Some((receiver, sent_at)) = &mut self.request {
+            match receiver.try_recv() {
+                Ok(Some(Ok(rooms))) => {
+                    self.latency = sent_at.elapsed();
+                    self.rooms = rooms;
+                    self.error = None;
+                    self.request = None;
+                }
+                Ok(Some(Err(e))) => {
+                    log::warn!("Could not list the rooms in the lobby: {:?}", e);
+                    self.error = Some("Could not reach the lobby".to_string());
+                    self.request = None;
+                }
+                Ok(None) => {}
+                Err(_) => self.request = None,
+            }
+        } else if Instant::now() >= self.next_refresh {
+            self.next_refresh = Instant::now() + LOBBY_REFRESH_INTERVAL;
+            self.request = Some((
+                lobby::spawn(self.provider.list_rooms(&super::rom_hash())),
+                Instant::now(),
+            ));
+        }
+    }
+}
+
+fn format_age(age: Duration) -> String {
+    match age.as_secs() {
+        secs @ 0..=59 => format!("{secs}s"),
+        secs @ 60..=3599 => format!("{}m", secs / 60),
+        secs => format!("{}h", secs / 3600),
+    }
+}
+
+struct ReplaysGui {
+    replays: Vec<PathBuf>,
+    error: Option<String>,
+}
+
+impl ReplaysGui {
+    fn new() -> Self {
+        Self {
+            replays: replay::list_replays(),
+            error: None,
+        }
+    }
+}
+
+/// Accepts an address with or without a port
+fn resolve(address: &str) -> Option<SocketAddr> {
+    address
+        .to_socket_addrs()
+        .or_else(|_| (address, DEFAULT_PORT).to_socket_addrs())
+        .ok()?
+        .next()
+}
+
 fn ui_text_small(text: impl Into<String>, color: Color32) -> RichText {
     RichText::new(text)
         .color(color)
@@ -78,17 +210,464 @@ impl NetplayGui {
             return None;
         }
 
-        Some(
-            match &netplay_state_handler.netplay {
-                // Connecting is a modal state, you can't see any messages when in the netplay UI anyway
-                Some(NetplayState::Connecting(_)) => None,
-                Some(NetplayState::Resuming(_)) => Some("Trying to reconnect...".to_string()),
-                _ => None,
+        let mut messages: Vec<String> = match &netplay_state_handler.netplay {
+            // Connecting is a modal state, you can't see any messages when in the netplay UI anyway
+            Some(NetplayState::Connecting(_)) => None,
+            Some(NetplayState::Resuming(_)) => Some("Trying to reconnect...".to_string()),
+            Some(NetplayState::Spectating(netplay_spectating))
+                if !netplay_spectating.state.watching =>
+            {
+                Some("Waiting for the game to start...".to_string())
+            }
+            Some(NetplayState::Replaying(netplay_replaying)) => {
+                Some(match netplay_replaying.state.desync_frame {
+                    Some(frame) => format!("Replay differs from the recording at frame {frame}"),
+                    None => "Watching a replay".to_string(),
+                })
+            }
+            Some(NetplayState::Connected(netplay_connected))
+                if netplay_connected
+                    .state
+                    .netplay_session
+                    .desync_frame
+                    .is_some() =>
+            {
+                Some("Out of sync! Resync from the menu".to_string())
+            }
+            Some(NetplayState::Connected(netplay_connected)) => {
+                let netplay_session = &netplay_connected.state.netplay_session;
+                if let Some(deadline) = netplay_session.disconnect_deadline {
+                    Some(format!(
+                        "Connection interrupted! Disconnecting in {}s",
+                        deadline.saturating_duration_since(Instant::now()).as_secs() + 1
+                    ))
+                } else if matches!(
+                    &netplay_session.connection_stats,
+                    Some(ConnectionStats {
+                        quality: ConnectionQuality::Poor,
+                        ..
+                    })
+                ) {
+                    Some("Poor connection, the game might stutter".to_string())
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+        .iter()
+        .map(|msg| format!("{} - {msg}", self.name().expect("a name")))
+        .collect();
+
+        if let Some(NetplayState::Connected(netplay_connected)) = &netplay_state_handler.netplay {
+            messages.extend(
+                netplay_connected
+                    .state
+                    .netplay_session
+                    .chat
+                    .iter()
+                    .filter(|message| message.received.elapsed() < CHAT_MESSAGE_DURATION)
+                    .map(|message| format!("{}: {}", message.from, message.text)),
+            );
+        }
+        Some(messages)
+    }
+
+    /// The connection stats shown on top of the game
+    pub fn overlay(&self, ctx: &egui::Context, netplay_state_handler: &NetplayStateHandler) {
+        if !Settings::current().show_netplay_hud {
+            return;
+        }
+        let Some(NetplayState::Connected(netplay_connected)) = &netplay_state_handler.netplay
+        else {
+            return;
+        };
+        let Some(stats) = &netplay_connected.state.netplay_session.connection_stats else {
+            return;
+        };
+
+        let (quality, color) = match stats.quality {
+            ConnectionQuality::Good => ("GOOD", Color32::from_rgb(80, 255, 80)),
+            ConnectionQuality::Fair => ("FAIR", Color32::from_rgb(255, 200, 80)),
+            ConnectionQuality::Poor => ("POOR", Color32::from_rgb(255, 80, 80)),
+        };
+        egui::Area::new(egui::Id::new("netplay_hud"))
+            .anchor(egui::Align2::RIGHT_TOP, [-10.0, 10.0])
+            .interactable(false)
+            .show(ctx, |ui| {
+                egui::Frame::none()
+                    .fill(Color32::from_black_alpha(160))
+                    .inner_margin(egui::Margin::same(6.0))
+                    .show(ui, |ui| {
+                        ui.label(ui_text_small(format!("CONNECTION {quality}"), color));
+                        for line in [
+                            format!("PING {}ms", stats.ping),
+                            format!(
+                                "ADVANTAGE {:+} / {:+}",
+                                stats.local_frame_advantage, stats.remote_frame_advantage
+                            ),
+                            format!("ROLLBACKS {}/s", stats.rollbacks_per_second),
+                        ] {
+                            ui.label(ui_text_small(line, Color32::WHITE));
+                        }
+                    });
+            });
+    }
+
+    fn ui_direct(
+        &mut self,
+        ui: &mut Ui,
+        netplay_disconnected: Netplay<LocalNesState>,
+    ) -> NetplayState {
+        enum Action {
+            Host(u16),
+            Join(SocketAddr),
+            Cancel,
+        }
+        let mut action = None;
+        let Some(direct) = &mut self.direct else {
+            return NetplayState::Disconnected(netplay_disconnected);
+        };
+
+        ui.vertical_centered(|ui| {
+            Label::new(MenuButton::ui_text(
+                "DIRECT CONNECTION",
+                MenuButton::ACTIVE_COLOR,
+            ))
+            .selectable(false)
+            .ui(ui);
+        });
+        ui.end_row();
+        ui.add_space(10.0);
+        ui.end_row();
+
+        ui.vertical_centered(|ui| {
+            egui::Grid::new("direct_grid")
+                .num_columns(3)
+                .spacing([10.0, 10.0])
+                .show(ui, |ui| {
+                    ui.label("Port");
+                    ui.add(TextEdit::singleline(&mut direct.port).desired_width(160.0));
+                    let port = direct.port.trim().parse::<u16>().ok();
+                    if ui
+                        .add_enabled(port.is_some(), Button::new("Host"))
+                        .clicked()
+                    {
+                        action = port.map(Action::Host);
+                    }
+                    ui.end_row();
+
+                    ui.label("Address");
+                    ui.add(
+                        TextEdit::singleline(&mut direct.address)
+                            .hint_text(format!("192.168.0.2:{DEFAULT_PORT}"))
+                            .desired_width(160.0),
+                    );
+                    if ui
+                        .add_enabled(!direct.address.trim().is_empty(), Button::new("Join"))
+                        .clicked()
+                    {
+                        match resolve(direct.address.trim()) {
+                            Some(addr) => action = Some(Action::Join(addr)),
+                            None => direct.error = Some("Unknown address".to_string()),
+                        }
+                    }
+                    ui.end_row();
+                });
+            if let Some(error) = &direct.error {
+                ui.colored_label(Color32::from_rgb(255, 80, 80), error);
+            }
+        });
+        ui.end_row();
+        ui.add_space(10.0);
+        ui.end_row();
+
+        if let Some(discovery) = &mut direct.discovery {
+            let games = discovery.poll();
+            ui.vertical_centered(|ui| {
+                Label::new(ui_text_small("ON YOUR NETWORK", MenuButton::ACTIVE_COLOR))
+                    .selectable(false)
+                    .ui(ui);
+                if games.is_empty() {
+                    ui.label("No games found yet");
+                }
+                for game in games {
+                    if ui
+                        .button(format!("{} ({})", game.name, game.addr))
+                        .clicked()
+                    {
+                        action = Some(Action::Join(game.addr));
+                    }
+                }
+            });
+            ui.end_row();
+        }
+
+        ui.vertical_centered(|ui| {
+            if ui_button("Cancel").ui(ui).clicked() || esc_pressed(ui.ctx()) {
+                action = Some(Action::Cancel);
+            }
+        });
+        self.last_screen = Some("DIRECT");
+
+        match action {
+            Some(Action::Host(port)) => {
+                self.direct = None;
+                netplay_disconnected
+                    .host_direct(port)
+                    .expect("to be able to host a direct game")
+            }
+            Some(Action::Join(addr)) => {
+                self.direct = None;
+                netplay_disconnected
+                    .join_direct(addr)
+                    .expect("to be able to join a direct game")
+            }
+            Some(Action::Cancel) => {
+                self.direct = None;
+                NetplayState::Disconnected(netplay_disconnected)
+            }
+            None => NetplayState::Disconnected(netplay_disconnected),
+        }
+    }
+
+    fn ui_lobby(
+        &mut self,
+        ui: &mut Ui,
+        netplay_disconnected: Netplay<LocalNesState>,
+    ) -> NetplayState {
+        enum Action {
+            Join(String),
+            Refresh,
+            Cancel,
+        }
+        let mut action = None;
+        let Some(lobby) = &mut self.lobby else {
+            return NetplayState::Disconnected(netplay_disconnected);
+        };
+        lobby.poll();
+
+        ui.vertical_centered(|ui| {
+            Label::new(MenuButton::ui_text(
+                "PUBLIC GAMES",
+                MenuButton::ACTIVE_COLOR,
+            ))
+            .selectable(false)
+            .ui(ui);
+        });
+        ui.end_row();
+        ui.add_space(10.0);
+        ui.end_row();
+
+        ui.vertical_centered(|ui| {
+            if lobby.rooms.is_empty() {
+                ui.label(if lobby.request.is_some() {
+                    "Looking for games..."
+                } else {
+                    "No open games right now"
+                });
+            } else {
+                egui::Grid::new("lobby_grid")
+                    .num_columns(5)
+                    .spacing([20.0, 10.0])
+                    .show(ui, |ui| {
+                        for header in ["HOST", "REGION", "PING", "AGE"] {
+                            Label::new(ui_text_small(header, MenuButton::ACTIVE_COLOR))
+                                .selectable(false)
+                                .ui(ui);
+                        }
+                        ui.end_row();
+
+                        for room in &lobby.rooms {
+                            ui.label(&room.host_name);
+                            ui.label(room.region.as_deref().unwrap_or("-"));
+                            ui.label(format!(
+                                "{}ms",
+                                room.ping_estimate(lobby.latency).as_millis()
+                            ));
+                            ui.label(format_age(room.age()));
+                            if ui.button("Join").clicked() {
+                                action = Some(Action::Join(room.room_name.clone()));
+                            }
+                            ui.end_row();
+                        }
+                    });
+            }
+            if let Some(error) = &lobby.error {
+                ui.colored_label(Color32::from_rgb(255, 80, 80), error);
+            }
+        });
+        ui.end_row();
+        ui.add_space(10.0);
+        ui.end_row();
+
+        ui.vertical_centered(|ui| {
+            if ui_button("Refresh").ui(ui).clicked() {
+                action = Some(Action::Refresh);
+            }
+            if ui_button("Cancel").ui(ui).clicked() || esc_pressed(ui.ctx()) {
+                action = Some(Action::Cancel);
+            }
+        });
+        self.last_screen = Some("LOBBY");
+
+        match action {
+            Some(Action::Join(room_name)) => {
+                self.lobby = None;
+                netplay_disconnected
+                    .join_game(&room_name)
+                    .expect("to be able to join game")
+            }
+            Some(Action::Refresh) => {
+                lobby.next_refresh = Instant::now();
+                NetplayState::Disconnected(netplay_disconnected)
+            }
+            Some(Action::Cancel) => {
+                self.lobby = None;
+                NetplayState::Disconnected(netplay_disconnected)
+            }
+            None => NetplayState::Disconnected(netplay_disconnected),
+        }
+    }
+
+    fn ui_replays(
+        &mut self,
+        ui: &mut Ui,
+        netplay_disconnected: Netplay<LocalNesState>,
+    ) -> NetplayState {
+        enum Action {
+            Play(PathBuf),
+            Cancel,
+        }
+        let mut action = None;
+        let Some(replays) = &mut self.replays else {
+            return NetplayState::Disconnected(netplay_disconnected);
+        };
+
+        ui.vertical_centered(|ui| {
+            Label::new(MenuButton::ui_text("REPLAYS", MenuButton::ACTIVE_COLOR))
+                .selectable(false)
+                .ui(ui);
+        });
+        ui.end_row();
+        ui.add_space(10.0);
+        ui.end_row();
+
+        ui.vertical_centered(|ui| {
+            if replays.replays.is_empty() {
+                ui.label("Games played online are recorded here");
+            }
+            egui::ScrollArea::vertical()
+                .max_height(200.0)
+                .show(ui, |ui| {
+                    for path in &replays.replays {
+                        let name = path
+                            .file_stem()
+                            .map(|name| name.to_string_lossy().to_string())
+                            .unwrap_or_default();
+                        if ui.button(name).clicked() {
+                            action = Some(Action::Play(path.clone()));
+                        }
+                    }
+                });
+            if let Some(error) = &replays.error {
+                ui.colored_label(Color32::from_rgb(255, 80, 80), error);
+            }
+        });
+        ui.end_row();
+        ui.add_space(10.0);
+        ui.end_row();
+
+        ui.vertical_centered(|ui| {
+            if ui_button("Cancel").ui(ui).clicked() || esc_pressed(ui.ctx()) {
+                action = Some(Action::Cancel);
+            }
+        });
+        self.last_screen = Some("REPLAYS");
+
+        match action {
+            Some(Action::Play(path)) => match ReplayPlayback::load(&path) {
+                Ok(playback) => {
+                    self.replays = None;
+                    MainGui::set_main_menu_state(MainMenuState::Closed);
+                    netplay_disconnected.play_replay(playback)
+                }
+                Err(e) => {
+                    log::error!("Could not load replay {:?}: {:?}", path, e);
+                    replays.error = Some(format!("Could not load the replay: {e}"));
+                    NetplayState::Disconnected(netplay_disconnected)
+                }
+            },
+            Some(Action::Cancel) => {
+                self.replays = None;
+                NetplayState::Disconnected(netplay_disconnected)
+            }
+            None => NetplayState::Disconnected(netplay_disconnected),
+        }
+    }
+
+    fn ui_replaying(
+        &mut self,
+        ui: &mut Ui,
+        netplay_replaying: Netplay<ReplayPlayback>,
+    ) -> NetplayState {
+        let playback = &netplay_replaying.state;
+        ui.vertical_centered(|ui| {
+            Label::new(MenuButton::ui_text(
+                "WATCHING REPLAY",
+                MenuButton::ACTIVE_COLOR,
+            ))
+            .selectable(false)
+            .ui(ui);
+        });
+        ui.end_row();
+
+        ui.vertical_centered(|ui| {
+            for (handle, name) in playback.replay.player_names.iter().enumerate() {
+                Label::new(ui_text_small(
+                    format!("P{} {}", handle + 1, name.as_deref().unwrap_or("?")),
+                    MenuButton::ACTIVE_COLOR,
+                ))
+                .selectable(false)
+                .ui(ui);
             }
-            .iter()
-            .map(|msg| format!("{} - {msg}", self.name().expect("a name")))
-            .collect(),
-        )
+            Label::new(ui_text_small(
+                format!(
+                    "FRAME {} / {}",
+                    playback.game_state.frame,
+                    playback.frames()
+                ),
+                MenuButton::ACTIVE_COLOR,
+            ))
+            .selectable(false)
+            .ui(ui);
+            if let Some(frame) = playback.desync_frame {
+                Label::new(ui_text_small(
+                    format!("DIFFERS FROM THE RECORDING AT FRAME {frame}"),
+                    Color32::from_rgb(255, 80, 80),
+                ))
+                .selectable(false)
+                .ui(ui);
+            }
+        });
+        ui.end_row();
+        ui.add_space(10.0);
+        ui.end_row();
+
+        let stop_clicked = ui
+            .vertical_centered(|ui| ui_button("Stop").ui(ui).clicked())
+            .inner;
+        ui.end_row();
+
+        if esc_pressed(ui.ctx()) {
+            MainGui::set_main_menu_state(MainMenuState::Main);
+        }
+
+        if stop_clicked {
+            NetplayState::Disconnected(netplay_replaying.disconnect())
+        } else {
+            NetplayState::Replaying(netplay_replaying)
+        }
     }
 
     fn ui_disconnected(
@@ -96,6 +675,16 @@ impl NetplayGui {
         ui: &mut Ui,
         netplay_disconnected: Netplay<LocalNesState>,
     ) -> NetplayState {
+        if self.direct.is_some() {
+            return self.ui_direct(ui, netplay_disconnected);
+        }
+        if self.replays.is_some() {
+            return self.ui_replays(ui, netplay_disconnected);
+        }
+        if self.lobby.is_some() {
+            return self.ui_lobby(ui, netplay_disconnected);
+        }
+
         if let Some(room_name) = &mut self.room_name {
             enum Action {
                 Join(String),
@@ -105,7 +694,11 @@ impl NetplayGui {
 
             ui.vertical_centered(|ui| {
                 Label::new(MenuButton::ui_text(
-                    "JOIN PRIVATE GAME",
+                    if self.watch {
+                        "WATCH GAME"
+                    } else {
+                        "JOIN PRIVATE GAME"
+                    },
                     MenuButton::ACTIVE_COLOR,
                 ))
                 .selectable(false)
@@ -119,6 +712,20 @@ impl NetplayGui {
                     .ui(ui);
             });
             ui.end_row();
+
+            if self.watch {
+                // GGRS can't add spectators to a running session
+                ui.vertical_centered(|ui| {
+                    Label::new(ui_text_small(
+                        "JOIN BEFORE THE GAME STARTS, IT CAN'T BE WATCHED ONCE IT'S RUNNING",
+                        MenuButton::ACTIVE_COLOR,
+                    ))
+                    .wrap(true)
+                    .selectable(false)
+                    .ui(ui);
+                });
+                ui.end_row();
+            }
             ui.add_space(10.0);
 
             ui.end_row();
@@ -180,6 +787,11 @@ impl NetplayGui {
             if let Some(action) = action {
                 self.room_name = None;
                 match action {
+                    Action::Join(room_name) if self.watch => {
+                        return netplay_disconnected
+                            .watch_game(&room_name)
+                            .expect("to be able to watch game");
+                    }
                     Action::Join(room_name) => {
                         return netplay_disconnected
                             .join_game(&room_name)
@@ -190,12 +802,64 @@ impl NetplayGui {
         } else {
             enum Action {
                 Find,
+                Browse,
                 Join,
                 Host,
+                HostPublic,
+                Watch,
+                Direct,
+                Replays,
             }
 
             let mut action = None;
 
+            ui.vertical_centered(|ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Your name");
+                    let default_name = get_player_name();
+                    let mut settings = Settings::current_mut();
+                    ui.add(
+                        TextEdit::singleline(settings.player_name.get_or_insert_with(String::new))
+                            .hint_text(default_name)
+                            .char_limit(MAX_PLAYER_NAME_LEN)
+                            .desired_width(160.0),
+                    );
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Input delay when hosting");
+                    let mut settings = Settings::current_mut();
+                    let input_delay = &mut settings.netplay_input_delay;
+                    egui::ComboBox::from_id_source("netplay_input_delay")
+                        .selected_text(input_delay.to_string())
+                        .show_ui(ui, |ui| {
+                            for option in [NetplayInputDelay::Configured, NetplayInputDelay::Auto]
+                                .into_iter()
+                                .chain((0..=MAX_INPUT_DELAY).map(NetplayInputDelay::Fixed))
+                            {
+                                ui.selectable_value(input_delay, option, option.to_string());
+                            }
+                        });
+                });
+                if Bundle::current().config.netplay.lobby.is_some() {
+                    ui.horizontal(|ui| {
+                        ui.label("Your region");
+                        let mut settings = Settings::current_mut();
+                        let region = &mut settings.netplay_region;
+                        egui::ComboBox::from_id_source("netplay_region")
+                            .selected_text(region.as_deref().unwrap_or("Not set"))
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(region, None, "Not set");
+                                for option in LOBBY_REGIONS {
+                                    ui.selectable_value(region, Some(option.to_string()), option);
+                                }
+                            });
+                    });
+                }
+            });
+            ui.end_row();
+            ui.add_space(10.0);
+            ui.end_row();
+
             ui.vertical_centered(|ui| {
                 if MenuButton::new("FIND PUBLIC GAME").ui(ui).clicked() {
                     action = Some(Action::Find);
@@ -203,6 +867,22 @@ impl NetplayGui {
             });
             ui.end_row();
 
+            if Bundle::current().config.netplay.lobby.is_some() {
+                ui.vertical_centered(|ui| {
+                    if MenuButton::new("BROWSE GAMES").ui(ui).clicked() {
+                        action = Some(Action::Browse);
+                    }
+                });
+                ui.end_row();
+
+                ui.vertical_centered(|ui| {
+                    if MenuButton::new("HOST PUBLIC GAME").ui(ui).clicked() {
+                        action = Some(Action::HostPublic);
+                    }
+                });
+                ui.end_row();
+            }
+
             ui.vertical_centered(|ui| {
                 if MenuButton::new("HOST PRIVATE GAME").ui(ui).clicked() {
                     action = Some(Action::Host);
@@ -216,6 +896,27 @@ impl NetplayGui {
                 }
             });
             ui.end_row();
+
+            ui.vertical_centered(|ui| {
+                if MenuButton::new("WATCH GAME").ui(ui).clicked() {
+                    action = Some(Action::Watch);
+                }
+            });
+            ui.end_row();
+
+            ui.vertical_centered(|ui| {
+                if MenuButton::new("DIRECT CONNECTION").ui(ui).clicked() {
+                    action = Some(Action::Direct);
+                }
+            });
+            ui.end_row();
+
+            ui.vertical_centered(|ui| {
+                if MenuButton::new("REPLAYS").ui(ui).clicked() {
+                    action = Some(Action::Replays);
+                }
+            });
+            ui.end_row();
             ui.vertical_centered(|ui| {
                 if ui_button("Close").ui(ui).clicked() || esc_pressed(ui.ctx()) {
                     self.room_name = None;
@@ -232,12 +933,27 @@ impl NetplayGui {
                             .find_game()
                             .expect("to be able to find a game");
                     }
-                    Action::Join => self.room_name = Some(String::new()),
+                    Action::Join => {
+                        self.room_name = Some(String::new());
+                        self.watch = false;
+                    }
+                    Action::Watch => {
+                        self.room_name = Some(String::new());
+                        self.watch = true;
+                    }
+                    Action::Browse => self.lobby = lobby::lobby_provider().map(LobbyGui::new),
+                    Action::Direct => self.direct = Some(DirectGui::new()),
+                    Action::Replays => self.replays = Some(ReplaysGui::new()),
                     Action::Host => {
                         return netplay_disconnected
                             .host_game()
                             .expect("to be able to host a game");
                     }
+                    Action::HostPublic => {
+                        return netplay_disconnected
+                            .host_public_game()
+                            .expect("to be able to host a game");
+                    }
                 }
             }
         }
@@ -285,12 +1001,38 @@ impl NetplayGui {
                                 .ui(ui);
                             });
                         }
+
+                        HostPublic => {
+                            ui.vertical_centered(|ui| {
+                                Label::new(MenuButton::ui_text(
+                                    "HOSTING PUBLIC GAME",
+                                    MenuButton::ACTIVE_COLOR,
+                                ))
+                                .selectable(false)
+                                .ui(ui);
+                            });
+                        }
+
+                        Watch => {
+                            ui.vertical_centered(|ui| {
+                                Label::new(MenuButton::ui_text(
+                                    "WATCHING GAME",
+                                    MenuButton::ACTIVE_COLOR,
+                                ))
+                                .selectable(false)
+                                .ui(ui);
+                            });
+                        }
                     }
                     ui.end_row();
 
                     ui.vertical_centered(|ui| {
                         Label::new(ui_text_small(
-                            "WAITING FOR SECOND PLAYER",
+                            if let Watch = join_or_host {
+                                "WAITING FOR THE PLAYERS"
+                            } else {
+                                "WAITING FOR SECOND PLAYER"
+                            },
                             MenuButton::ACTIVE_COLOR,
                         ))
                         .selectable(false)
@@ -338,6 +1080,68 @@ impl NetplayGui {
                 StartMethod::Resume(_) => {
                     //This is used internally during the `NetplayState::Resuming` state
                 }
+                StartMethod::Direct(..) => {
+                    //Direct connections don't use a netplay server
+                }
+            },
+            ConnectingState::DirectPeeringUp(Connecting { state, .. }) => match &state.connection {
+                DirectConnection::Host(port) => {
+                    ui.vertical_centered(|ui| {
+                        Label::new(MenuButton::ui_text(
+                            "HOSTING DIRECT GAME",
+                            MenuButton::ACTIVE_COLOR,
+                        ))
+                        .selectable(false)
+                        .ui(ui);
+                    });
+                    ui.end_row();
+
+                    ui.vertical_centered(|ui| {
+                        Label::new(ui_text_small(
+                            "WAITING FOR SECOND PLAYER",
+                            MenuButton::ACTIVE_COLOR,
+                        ))
+                        .selectable(false)
+                        .ui(ui);
+                    });
+                    ui.end_row();
+                    ui.add_space(10.0);
+                    ui.end_row();
+
+                    ui.vertical_centered(|ui| {
+                        Label::new(MenuButton::ui_text("PORT", MenuButton::ACTIVE_COLOR))
+                            .selectable(false)
+                            .ui(ui);
+                    });
+                    ui.end_row();
+                    ui.vertical_centered(|ui| {
+                        Label::new(MenuButton::ui_text(
+                            port.to_string(),
+                            Color32::from_rgb(255, 225, 0),
+                        ))
+                        .ui(ui);
+                    });
+                }
+                DirectConnection::Join(addr) => {
+                    ui.vertical_centered(|ui| {
+                        Label::new(MenuButton::ui_text(
+                            "JOINING DIRECT GAME",
+                            MenuButton::ACTIVE_COLOR,
+                        ))
+                        .selectable(false)
+                        .ui(ui);
+                    });
+                    ui.end_row();
+
+                    ui.vertical_centered(|ui| {
+                        Label::new(ui_text_small(
+                            format!("CONNECTING TO {addr}"),
+                            MenuButton::ACTIVE_COLOR,
+                        ))
+                        .selectable(false)
+                        .ui(ui);
+                    });
+                }
             },
             ConnectingState::Synchronizing(synchronizing_state) => {
                 ui.vertical_centered(|ui| {
@@ -416,7 +1220,11 @@ impl NetplayGui {
         NetplayState::Connecting(netplay_connecting)
     }
 
-    fn ui_connected(&mut self, ui: &mut Ui, netplay_connected: Netplay<Connected>) -> NetplayState {
+    fn ui_connected(
+        &mut self,
+        ui: &mut Ui,
+        mut netplay_connected: Netplay<Connected>,
+    ) -> NetplayState {
         // Hide menu if we just managed to connect
         if Instant::now()
             .duration_since(netplay_connected.state.start_time)
@@ -433,13 +1241,90 @@ impl NetplayGui {
         });
         ui.end_row();
 
+        let netplay_session = &mut netplay_connected.state.netplay_session;
+        ui.vertical_centered(|ui| {
+            let local_player_idx = netplay_session.get_local_player_idx();
+            for handle in 0..MAX_PLAYERS {
+                let you = if handle == local_player_idx {
+                    " (YOU)"
+                } else {
+                    ""
+                };
+                Label::new(ui_text_small(
+                    format!(
+                        "P{} {}{you}",
+                        handle + 1,
+                        netplay_session.player_name(handle)
+                    ),
+                    MenuButton::ACTIVE_COLOR,
+                ))
+                .selectable(false)
+                .ui(ui);
+            }
+            Label::new(ui_text_small(
+                format!("INPUT DELAY {} FRAMES", netplay_session.input_delay),
+                MenuButton::ACTIVE_COLOR,
+            ))
+            .selectable(false)
+            .ui(ui);
+        });
+        ui.end_row();
+        ui.add_space(10.0);
+        ui.end_row();
+
+        ui.vertical_centered(|ui| {
+            for message in &netplay_session.chat {
+                ui.label(format!("{}: {}", message.from, message.text));
+            }
+            ui.horizontal(|ui| {
+                let re = ui.add(
+                    TextEdit::singleline(&mut self.chat_input)
+                        .hint_text("Say something")
+                        .char_limit(MAX_CHAT_MESSAGE_LEN)
+                        .desired_width(240.0),
+                );
+                let enter_pressed =
+                    re.lost_focus() && re.ctx.input(|i| i.key_pressed(egui::Key::Enter));
+                if ui.button("Send").clicked() || enter_pressed {
+                    netplay_session.send_chat(&self.chat_input);
+                    self.chat_input.clear();
+                    re.request_focus();
+                }
+            });
+        });
+        ui.end_row();
+
         #[allow(dead_code)] // Some actions are only triggered by certain features
         enum Action {
             FakeDisconnect,
             Disconnect,
+            Resync,
         }
 
         let mut action = None;
+        if let Some(desync_frame) = netplay_connected.state.netplay_session.desync_frame {
+            ui.vertical_centered(|ui| {
+                Label::new(ui_text_small("OUT OF SYNC", Color32::from_rgb(255, 80, 80)))
+                    .selectable(false)
+                    .ui(ui);
+                ui.label(format!(
+                    "Your games stopped matching at frame {desync_frame}.\n\
+                     Resync to continue from the host's game."
+                ));
+                if ui_button("Resync").ui(ui).clicked() {
+                    action = Some(Action::Resync);
+                }
+            });
+            ui.end_row();
+        }
+        ui.vertical_centered(|ui| {
+            ui.checkbox(
+                &mut Settings::current_mut().show_netplay_hud,
+                "Show the connection stats on top of the game",
+            );
+        });
+        ui.end_row();
+
         ui.vertical_centered(|ui| {
             if ui_button("Disconnect").ui(ui).clicked() {
                 action = Some(Action::Disconnect);
@@ -469,11 +1354,12 @@ impl NetplayGui {
             match action {
                 Action::FakeDisconnect => {
                     log::debug!("Manually resuming connection (faking a lost connection)");
-                    return NetplayState::Resuming(netplay_connected.resume());
+                    return netplay_connected.connection_lost();
                 }
                 Action::Disconnect => {
                     return NetplayState::Disconnected(netplay_connected.disconnect());
                 }
+                Action::Resync => netplay_connected.state.netplay_session.resync(),
             }
         }
         NetplayState::Connected(netplay_connected)
@@ -489,6 +1375,40 @@ impl NetplayGui {
                 self.ui_connecting(ui, netplay_connecting)
             }
             NetplayState::Connected(netplay_connected) => self.ui_connected(ui, netplay_connected),
+            NetplayState::Spectating(netplay_spectating) => {
+                ui.vertical_centered(|ui| {
+                    Label::new(MenuButton::ui_text("WATCHING", MenuButton::ACTIVE_COLOR))
+                        .selectable(false)
+                        .ui(ui);
+                });
+                ui.end_row();
+                if !netplay_spectating.state.watching {
+                    ui.vertical_centered(|ui| {
+                        Label::new(ui_text_small(
+                            "WAITING FOR THE GAME TO START",
+                            MenuButton::ACTIVE_COLOR,
+                        ))
+                        .selectable(false)
+                        .ui(ui);
+                    });
+                    ui.end_row();
+                }
+                let stop_clicked = ui
+                    .vertical_centered(|ui| ui_button("Stop watching").ui(ui).clicked())
+                    .inner;
+                ui.end_row();
+
+                if esc_pressed(ui.ctx()) {
+                    MainGui::set_main_menu_state(MainMenuState::Main);
+                }
+
+                if stop_clicked {
+                    NetplayState::Disconnected(netplay_spectating.disconnect())
+                } else {
+                    NetplayState::Spectating(netplay_spectating)
+                }
+            }
+            NetplayState::Replaying(netplay_replaying) => self.ui_replaying(ui, netplay_replaying),
             NetplayState::Resuming(netplay_resuming) => {
                 ui.vertical_centered(|ui| {
                     Label::new(MenuButton::ui_text("RESUMING...", MenuButton::ACTIVE_COLOR))
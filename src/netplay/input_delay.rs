@@ -0,0 +1,115 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::settings::{NetplayInputDelay, Settings};
+
+use super::{connecting_state::GGRSConfiguration, side_channel::SideChannelMessage};
+
+pub const MAX_INPUT_DELAY: u8 = 8;
+/// How many round trips to measure before picking the input delay automatically
+const RTT_SAMPLES: usize = 5;
+const PING_INTERVAL: Duration = Duration::from_millis(100);
+/// Stop measuring after this long and go with what we have
+const MEASURE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Lets the host pick the GGRS configuration both players will use
+pub struct InputDelayNegotiation {
+    ggrs_config: GGRSConfiguration,
+    /// Set when the input delay is picked from the round trip time
+    rtt: Option<RttMeasurement>,
+}
+
+struct RttMeasurement {
+    started: Instant,
+    next_ping: Instant,
+    next_id: u32,
+    sent: HashMap<u32, Instant>,
+    samples: Vec<Duration>,
+}
+
+impl RttMeasurement {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            next_ping: Instant::now(),
+            next_id: 0,
+            sent: HashMap::new(),
+            samples: Vec::new(),
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.samples.len() >= RTT_SAMPLES || self.started.elapsed() > MEASURE_TIMEOUT
+    }
+
+    /// The median of the samples
+    fn rtt(&self) -> Option<Duration> {
+        let mut samples = self.samples.clone();
+        samples.sort();
+        samples.get(samples.len() / 2).copied()
+    }
+}
+
+impl InputDelayNegotiation {
+    pub fn new(mut ggrs_config: GGRSConfiguration) -> Self {
+        let rtt = match Settings::current().netplay_input_delay {
+            NetplayInputDelay::Configured => None,
+            NetplayInputDelay::Fixed(frames) => {
+                ggrs_config.input_delay = frames.into();
+                None
+            }
+            NetplayInputDelay::Auto => Some(RttMeasurement::new()),
+        };
+        Self { ggrs_config, rtt }
+    }
+
+    pub fn pong(&mut self, id: u32) {
+        if let Some(rtt) = &mut self.rtt {
+            if let Some(sent_at) = rtt.sent.remove(&id) {
+                rtt.samples.push(sent_at.elapsed());
+            }
+        }
+    }
+
+    /// Returns a message for the other players, and the configuration once it has been picked
+    pub fn advance(&mut self) -> (Option<SideChannelMessage>, Option<GGRSConfiguration>) {
+        let Some(rtt) = &mut self.rtt else {
+            return (None, Some(self.ggrs_config.clone()));
+        };
+        if rtt.done() {
+            match rtt.rtt() {
+                Some(round_trip) => {
+                    self.ggrs_config.input_delay = auto_input_delay(round_trip);
+                    log::info!(
+                        "Round trip time is {:?}, using an input delay of {} frames",
+                        round_trip,
+                        self.ggrs_config.input_delay
+                    );
+                }
+                None => log::warn!(
+                    "Could not measure the round trip time, using the default input delay"
+                ),
+            }
+            self.rtt = None;
+            return (None, Some(self.ggrs_config.clone()));
+        }
+
+        if Instant::now() < rtt.next_ping {
+            return (None, None);
+        }
+        rtt.next_ping = Instant::now() + PING_INTERVAL;
+        let id = rtt.next_id;
+        rtt.next_id += 1;
+        rtt.sent.insert(id, Instant::now());
+        (Some(SideChannelMessage::Ping { id }), None)
+    }
+}
+
+/// Enough delay to cover the time it takes for inputs to reach the other player
+fn auto_input_delay(round_trip: Duration) -> usize {
+    let frame_time = 1.0 / Settings::current_mut().get_nes_region().to_fps();
+    let frames = (round_trip.as_secs_f32() / 2.0 / frame_time).ceil() as u8;
+    frames.clamp(1, MAX_INPUT_DELAY).into()
+}
@@ -0,0 +1,208 @@
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use futures::{
+    channel::oneshot::{self, Receiver},
+    future::BoxFuture,
+    FutureExt,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{bundle::Bundle, settings::Settings};
+
+use super::{netplay_state::get_player_name, rom_hash};
+
+/// How often a host tells the lobby that its room is still open
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// The regions a host can say they are in
+pub const LOBBY_REGIONS: [&str; 6] = [
+    "Africa",
+    "Asia",
+    "Europe",
+    "North America",
+    "Oceania",
+    "South America",
+];
+
+#[derive(Deserialize, Clone, Debug)]
+pub enum LobbyConfiguration {
+    /// A lobby server speaking JSON over HTTP
+    Http(String),
+}
+
+/// An open room waiting for a second player
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LobbyRoom {
+    pub room_name: String,
+    pub rom_hash: String,
+    pub host_name: String,
+    /// Where the host is, if they have said
+    #[serde(default)]
+    pub region: Option<String>,
+    /// The round trip time between the host and the lobby server
+    #[serde(default)]
+    pub host_latency_ms: Option<u32>,
+    /// When the room was opened, in seconds since the unix epoch
+    pub created_at: u64,
+}
+
+impl LobbyRoom {
+    pub fn age(&self) -> Duration {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Duration::from_secs(now.saturating_sub(self.created_at))
+    }
+
+    /// The round trip time to the host going through the lobby server is the best guess we have
+    pub fn ping_estimate(&self, lobby_latency: Duration) -> Duration {
+        lobby_latency + Duration::from_millis(self.host_latency_ms.unwrap_or_default().into())
+    }
+}
+
+/// Somewhere to list and publish open rooms
+pub trait LobbyProvider: Send + Sync {
+    fn list_rooms(&self, rom_hash: &str) -> BoxFuture<'static, Result<Vec<LobbyRoom>>>;
+    fn publish(&self, room: &LobbyRoom) -> BoxFuture<'static, Result<()>>;
+    fn unpublish(&self, room_name: &str) -> BoxFuture<'static, Result<()>>;
+}
+
+/// The lobby of the bundle, if it has one
+pub fn lobby_provider() -> Option<Arc<dyn LobbyProvider>> {
+    match &Bundle::current().config.netplay.lobby {
+        Some(LobbyConfiguration::Http(url)) => Some(Arc::new(HttpLobbyProvider::new(url))),
+        None => None,
+    }
+}
+
+/// Runs a lobby request in the background
+pub fn spawn<T: Send + 'static>(request: BoxFuture<'static, Result<T>>) -> Receiver<Result<T>> {
+    let (sender, result) = oneshot::channel();
+    tokio::spawn(async move {
+        let _ = sender.send(request.await);
+    });
+    result
+}
+
+/// Lists rooms with `GET {url}/rooms?rom_hash=..`, publishes them with `POST {url}/rooms` and
+/// removes them with `DELETE {url}/rooms/{room_name}`
+pub struct HttpLobbyProvider {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl HttpLobbyProvider {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl LobbyProvider for HttpLobbyProvider {
+    fn list_rooms(&self, rom_hash: &str) -> BoxFuture<'static, Result<Vec<LobbyRoom>>> {
+        let req = self
+            .client
+            .get(format!("{}/rooms", self.url))
+            .query(&[("rom_hash", rom_hash)])
+            .send();
+        async move { Ok(req.await?.error_for_status()?.json().await?) }.boxed()
+    }
+
+    fn publish(&self, room: &LobbyRoom) -> BoxFuture<'static, Result<()>> {
+        let req = self
+            .client
+            .post(format!("{}/rooms", self.url))
+            .json(room)
+            .send();
+        async move {
+            req.await?.error_for_status()?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn unpublish(&self, room_name: &str) -> BoxFuture<'static, Result<()>> {
+        let req = self
+            .client
+            .delete(format!("{}/rooms/{room_name}", self.url))
+            .send();
+        async move {
+            req.await?.error_for_status()?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// Keeps a hosted room listed in the lobby for as long as it lives
+pub struct LobbyListing {
+    provider: Arc<dyn LobbyProvider>,
+    room: LobbyRoom,
+    host_latency_ms: Arc<AtomicU32>,
+    next_heartbeat: Instant,
+}
+
+impl LobbyListing {
+    pub fn new(room_name: &str) -> Option<Self> {
+        let provider = lobby_provider()?;
+        Some(Self {
+            provider,
+            room: LobbyRoom {
+                room_name: room_name.to_string(),
+                rom_hash: rom_hash(),
+                host_name: get_player_name(),
+                region: Settings::current().netplay_region.clone(),
+                host_latency_ms: None,
+                created_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            },
+            host_latency_ms: Arc::new(AtomicU32::new(0)),
+            next_heartbeat: Instant::now(),
+        })
+    }
+
+    pub fn heartbeat(&mut self) {
+        if Instant::now() < self.next_heartbeat {
+            return;
+        }
+        self.next_heartbeat = Instant::now() + HEARTBEAT_INTERVAL;
+
+        let latency = self.host_latency_ms.load(Ordering::Relaxed);
+        self.room.host_latency_ms = (latency > 0).then_some(latency);
+        let publish = self.provider.publish(&self.room);
+        let host_latency_ms = self.host_latency_ms.clone();
+        tokio::spawn(async move {
+            let started = Instant::now();
+            match publish.await {
+                Ok(()) => host_latency_ms.store(
+                    started.elapsed().as_millis().max(1) as u32,
+                    Ordering::Relaxed,
+                ),
+                Err(e) => log::warn!("Could not publish room to the lobby: {:?}", e),
+            }
+        });
+    }
+}
+
+impl Drop for LobbyListing {
+    fn drop(&mut self) {
+        log::debug!("Removing room {} from the lobby", self.room.room_name);
+        let unpublish = self.provider.unpublish(&self.room.room_name);
+        tokio::spawn(async move {
+            if let Err(e) = unpublish.await {
+                log::warn!("Could not remove room from the lobby: {:?}", e);
+            }
+        });
+    }
+}
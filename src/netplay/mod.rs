@@ -1,7 +1,8 @@
 use std::ops::{Deref, DerefMut};
 
 use crate::{
-    emulation::{LocalNesState, NESBuffers, NesStateHandler},
+    bundle::Bundle,
+    emulation::{LocalNesState, NESBuffers, NesRegion, NesStateHandler},
     input::JoypadState,
     settings::MAX_PLAYERS,
 };
@@ -10,13 +11,21 @@ use serde::Deserialize;
 
 use self::{
     connecting_state::{ConnectingState, NetplayServerConfiguration, StartMethod, StartState},
+    lobby::LobbyConfiguration,
     netplay_state::{Netplay, NetplayState},
 };
 
+mod compatibility;
 mod connecting_state;
+mod direct;
 pub mod gui;
+mod input_delay;
+mod lobby;
 mod netplay_session;
 mod netplay_state;
+pub mod replay;
+mod side_channel;
+mod spectator_session;
 
 #[derive(Clone, Debug)]
 pub enum JoypadMapping {
@@ -53,6 +62,14 @@ impl JoypadMapping {
 pub struct NetplayBuildConfiguration {
     pub netplay_id: Option<String>,
     pub server: NetplayServerConfiguration,
+    /// Where public games are listed
+    #[serde(default)]
+    pub lobby: Option<LobbyConfiguration>,
+}
+
+/// Identifies the game, only players with the same netplay ROM can play together
+fn rom_hash() -> String {
+    format!("{:x}", md5::compute(&Bundle::current().netplay_rom))
 }
 
 pub struct NetplayStateHandler {
@@ -74,6 +91,17 @@ impl NetplayNesState {
             joypad_mapping: None,
         }
     }
+
+    /// A checksum of the game state, used to notice when the players' games have diverged
+    fn checksum(&self) -> Option<u128> {
+        match self.save_state() {
+            Ok(state) => Some(u128::from_le_bytes(md5::compute(state).0)),
+            Err(e) => {
+                log::warn!("Could not checksum frame {}: {:?}", self.frame, e);
+                None
+            }
+        }
+    }
 }
 
 impl Deref for NetplayNesState {
@@ -111,6 +139,8 @@ impl NesStateHandler for NetplayStateHandler {
     fn frame(&self) -> u32 {
         match &self.netplay {
             Some(NetplayState::Connected(s)) => s.state.netplay_session.game_state.frame(),
+            Some(NetplayState::Spectating(s)) => s.state.game_state.frame(),
+            Some(NetplayState::Replaying(s)) => s.state.game_state.frame(),
             Some(NetplayState::Disconnected(s)) => s.state.frame(),
             _ => 0,
         }
@@ -119,11 +149,52 @@ impl NesStateHandler for NetplayStateHandler {
     fn set_speed(&mut self, speed: f32) {
         match &mut self.netplay {
             Some(NetplayState::Connected(s)) => s.state.netplay_session.game_state.set_speed(speed),
+            Some(NetplayState::Spectating(s)) => s.state.game_state.set_speed(speed),
+            Some(NetplayState::Replaying(s)) => s.state.game_state.set_speed(speed),
             Some(NetplayState::Disconnected(s)) => s.state.set_speed(speed),
             _ => {}
         }
     }
 
+    fn speed(&self) -> f32 {
+        match &self.netplay {
+            Some(NetplayState::Connected(s)) => s.state.netplay_session.game_state.speed(),
+            Some(NetplayState::Spectating(s)) => s.state.game_state.speed(),
+            Some(NetplayState::Replaying(s)) => s.state.game_state.speed(),
+            Some(NetplayState::Disconnected(s)) => s.state.speed(),
+            _ => 1.0,
+        }
+    }
+
+    fn set_turbo_frames(&mut self, turbo_frames: u8) {
+        // When playing online everyone uses the turbo rate of the bundle
+        if let Some(NetplayState::Disconnected(s)) = &mut self.netplay {
+            s.state.set_turbo_frames(turbo_frames);
+        }
+    }
+
+    fn last_inputs(&self) -> [JoypadState; MAX_PLAYERS] {
+        match &self.netplay {
+            Some(NetplayState::Connected(s)) => s.state.netplay_session.game_state.last_inputs(),
+            Some(NetplayState::Spectating(s)) => s.state.game_state.last_inputs(),
+            Some(NetplayState::Replaying(s)) => s.state.game_state.last_inputs(),
+            Some(NetplayState::Disconnected(s)) => s.state.last_inputs(),
+            _ => [JoypadState(0); MAX_PLAYERS],
+        }
+    }
+
+    fn can_pause(&self) -> bool {
+        // Pausing would stall the other player(s)
+        matches!(
+            &self.netplay,
+            Some(
+                NetplayState::Disconnected(_)
+                    | NetplayState::Replaying(_)
+                    | NetplayState::Failed(_)
+            )
+        )
+    }
+
     fn reset(&mut self, hard: bool) {
         match &mut self.netplay {
             Some(NetplayState::Connected(s)) => s.state.netplay_session.game_state.reset(hard),
@@ -131,6 +202,13 @@ impl NesStateHandler for NetplayStateHandler {
             _ => {}
         }
     }
+
+    fn set_region(&mut self, region: &NesRegion) {
+        // A session keeps the region it was started in, the other players are running in it too
+        if let Some(NetplayState::Disconnected(s)) = &mut self.netplay {
+            s.state.set_region(region);
+        }
+    }
 }
 
 impl NetplayStateHandler {
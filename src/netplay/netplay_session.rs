@@ -1,5 +1,10 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
 use ggrs::{Config, GgrsRequest, P2PSession};
-use matchbox_socket::PeerId;
+use matchbox_socket::{PeerId, PeerState};
 
 use crate::{
     emulation::{NESBuffers, NesStateHandler},
@@ -7,41 +12,304 @@ use crate::{
     settings::MAX_PLAYERS,
 };
 
-use super::{connecting_state::StartMethod, JoypadMapping, NetplayNesState};
+use super::{
+    connecting_state::StartMethod,
+    netplay_state::MAX_PLAYER_NAME_LEN,
+    replay::ReplayRecorder,
+    side_channel::{SideChannel, SideChannelMessage},
+    JoypadMapping, NetplayNesState,
+};
 
 #[derive(Debug)]
 pub struct GGRSConfig;
 impl Config for GGRSConfig {
-    type Input = u8;
+    type Input = u16;
     type State = NetplayNesState;
     type Address = PeerId;
 }
 
+/// The matchbox channel GGRS runs on
+pub const GGRS_CHANNEL: usize = 0;
+/// A reliable matchbox channel for everything that isn't inputs
+pub const SIDE_CHANNEL: usize = 1;
+/// How often (in frames) the peers compare checksums of their game states
+pub const DESYNC_DETECTION_INTERVAL: u32 = 60;
+/// How long the host waits for the state to be acknowledged before resyncing anyway
+const RESYNC_ACK_TIMEOUT: Duration = Duration::from_secs(2);
+/// How many chat messages are kept around
+const MAX_CHAT_HISTORY: usize = 20;
+/// How often the connection stats are refreshed
+const CONNECTION_STATS_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionQuality {
+    Good,
+    Fair,
+    Poor,
+}
+
+impl ConnectionQuality {
+    fn new(ping: u128, frames_behind: i32, rollbacks_per_second: usize) -> Self {
+        if ping > 200 || frames_behind.abs() > 6 || rollbacks_per_second > 30 {
+            ConnectionQuality::Poor
+        } else if ping > 100 || frames_behind.abs() > 2 || rollbacks_per_second > 10 {
+            ConnectionQuality::Fair
+        } else {
+            ConnectionQuality::Good
+        }
+    }
+}
+
+/// How the connection to the other player is doing
+#[derive(Clone, Debug)]
+pub struct ConnectionStats {
+    /// Round trip time in milliseconds
+    pub ping: u128,
+    /// How many frames we are ahead of the other player (negative when behind)
+    pub local_frame_advantage: i32,
+    /// How many frames the other player is ahead of us (negative when behind)
+    pub remote_frame_advantage: i32,
+    pub rollbacks_per_second: usize,
+    pub quality: ConnectionQuality,
+}
+
+pub struct ChatMessage {
+    pub from: String,
+    pub text: String,
+    pub received: Instant,
+}
+
 pub struct NetplaySession {
     pub p2p_session: P2PSession<GGRSConfig>,
+    side_channel: SideChannel,
     pub game_state: NetplayNesState,
     pub last_handled_frame: i32,
     pub last_confirmed_game_states: [NetplayNesState; 2],
+    /// The first frame where the game states of the peers were found to differ
+    pub desync_frame: Option<i32>,
+    /// The host has sent its game state and is waiting for it to be acknowledged
+    resync_sent: Option<(Instant, NetplayNesState)>,
+    /// The game state to reconnect with to get back in sync
+    pub resync_state: Option<NetplayNesState>,
+    /// The names of the players, by player handle
+    pub player_names: [Option<String>; MAX_PLAYERS],
+    /// How many frames the inputs are delayed by, as picked by the host
+    pub input_delay: usize,
+    /// The latest chat messages, oldest first
+    pub chat: VecDeque<ChatMessage>,
+    pub connection_stats: Option<ConnectionStats>,
+    connection_stats_updated: Instant,
+    /// When the rollbacks of the last second happened
+    rollbacks: VecDeque<Instant>,
+    /// Set when the other player stopped responding, and when we will give up on them
+    pub disconnect_deadline: Option<Instant>,
+    recorder: Option<ReplayRecorder>,
 }
 
 impl NetplaySession {
-    pub fn new(start_method: StartMethod, p2p_session: P2PSession<GGRSConfig>) -> Self {
-        let mut game_state = match &start_method {
+    pub fn new(
+        start_method: StartMethod,
+        p2p_session: P2PSession<GGRSConfig>,
+        mut side_channel: SideChannel,
+        spectators: Vec<PeerId>,
+        player_names: [Option<String>; MAX_PLAYERS],
+        input_delay: usize,
+    ) -> Self {
+        let (mut game_state, session_id) = match &start_method {
             StartMethod::Start(start_state, ..)
             | StartMethod::Resume(start_state)
-            | StartMethod::MatchWithRandom(start_state) => start_state.clone().game_state,
+            | StartMethod::MatchWithRandom(start_state)
+            | StartMethod::Direct(start_state, _) => (
+                start_state.game_state.clone(),
+                start_state.session_id.clone(),
+            ),
         };
         //Start counting from 0 to be in sync with ggrs frame counter.
         game_state.frame = 0;
 
+        let local_player_idx = *p2p_session.local_player_handles().first().unwrap_or(&0);
+        let recorder = ReplayRecorder::new(&session_id, &game_state, local_player_idx, input_delay)
+            .map_err(|e| log::error!("Could not start recording a replay: {:?}", e))
+            .ok();
+
+        // The host sends the inputs to the spectators, and the game state to start from
+        if local_player_idx == 0 && !spectators.is_empty() {
+            match game_state.save_state() {
+                Ok(state) => {
+                    let message = SideChannelMessage::SpectatorState {
+                        frame: game_state.frame,
+                        state,
+                    };
+                    for spectator in spectators {
+                        side_channel.send(spectator, &message);
+                    }
+                }
+                Err(e) => log::error!("Could not save the game state for spectators: {:?}", e),
+            }
+        }
+
         Self {
             p2p_session,
+            side_channel,
             game_state: game_state.clone(),
             last_confirmed_game_states: [game_state.clone(), game_state],
             last_handled_frame: -1,
+            desync_frame: None,
+            resync_sent: None,
+            resync_state: None,
+            player_names,
+            input_delay,
+            chat: VecDeque::new(),
+            connection_stats: None,
+            connection_stats_updated: Instant::now(),
+            rollbacks: VecDeque::new(),
+            disconnect_deadline: None,
+            recorder,
+        }
+    }
+
+    /// The name of the player with this handle
+    pub fn player_name(&self, handle: usize) -> String {
+        self.player_names
+            .get(handle)
+            .cloned()
+            .flatten()
+            .unwrap_or_else(|| format!("Player {}", handle + 1))
+    }
+
+    pub fn send_chat(&mut self, text: &str) {
+        let text = text.trim();
+        if text.is_empty() {
+            return;
+        }
+        let handle = self.get_local_player_idx();
+        self.side_channel.send_to_all(&SideChannelMessage::Chat {
+            handle,
+            text: text.to_string(),
+        });
+        self.add_chat(handle, text.to_string());
+    }
+
+    fn add_chat(&mut self, handle: usize, text: String) {
+        if self.chat.len() == MAX_CHAT_HISTORY {
+            self.chat.pop_front();
+        }
+        self.chat.push_back(ChatMessage {
+            from: self.player_name(handle),
+            text,
+            received: Instant::now(),
+        });
+    }
+
+    /// The host is the one with the authoritative game state
+    pub fn is_host(&self) -> bool {
+        self.get_local_player_idx() == 0
+    }
+
+    /// Gets all players back to the game state of the host
+    pub fn resync(&mut self) {
+        if self.resync_sent.is_some() || self.resync_state.is_some() {
+            return;
+        }
+        if self.is_host() {
+            let game_state = self.last_confirmed_game_states[1].clone();
+            match game_state.save_state() {
+                Ok(state) => {
+                    log::info!("Sending game state at frame {} to resync", game_state.frame);
+                    self.side_channel
+                        .send_to_all(&SideChannelMessage::ResyncState {
+                            frame: game_state.frame,
+                            state,
+                        });
+                    self.resync_sent = Some((Instant::now(), game_state));
+                }
+                Err(e) => log::error!("Could not save the game state to resync: {:?}", e),
+            }
+        } else {
+            log::info!("Asking the host for its game state to resync");
+            self.side_channel
+                .send_to_all(&SideChannelMessage::ResyncRequest);
         }
     }
 
+    fn handle_side_channel(&mut self) {
+        for (peer, state) in self.side_channel.update_peers() {
+            if let PeerState::Connected = state {
+                // Too late to join, spectators need to be there when the game starts
+                self.side_channel
+                    .send(peer, &SideChannelMessage::AlreadyStarted);
+            }
+        }
+        for (peer, message) in self.side_channel.receive() {
+            match message {
+                SideChannelMessage::ResyncRequest if self.is_host() => self.resync(),
+                SideChannelMessage::ResyncState { frame, state } => {
+                    let mut game_state = self.game_state.clone();
+                    match game_state.load_state(&state) {
+                        Ok(()) => {
+                            log::info!("Received game state at frame {frame}, resyncing");
+                            game_state.frame = frame;
+                            self.side_channel
+                                .send_to_all(&SideChannelMessage::ResyncAck);
+                            self.resync_state = Some(game_state);
+                        }
+                        Err(e) => log::error!("Could not load the game state of the host: {:?}", e),
+                    }
+                }
+                SideChannelMessage::ResyncAck => {
+                    self.resync_state = self.resync_sent.take().map(|(_, state)| state);
+                }
+                SideChannelMessage::PlayerName { handle, name } if handle < MAX_PLAYERS => {
+                    self.player_names[handle] =
+                        Some(name.chars().take(MAX_PLAYER_NAME_LEN).collect());
+                }
+                SideChannelMessage::Chat { handle, text } => self.add_chat(handle, text),
+                message => log::warn!("Unexpected message from {:?}: {:?}", peer, message),
+            }
+        }
+
+        if let Some((sent_at, _)) = &self.resync_sent {
+            if sent_at.elapsed() > RESYNC_ACK_TIMEOUT {
+                log::warn!("Resync was not acknowledged, resyncing anyway");
+                self.resync_state = self.resync_sent.take().map(|(_, state)| state);
+            }
+        }
+    }
+
+    fn update_connection_stats(&mut self) {
+        while self
+            .rollbacks
+            .front()
+            .is_some_and(|rollback| rollback.elapsed() > Duration::from_secs(1))
+        {
+            self.rollbacks.pop_front();
+        }
+
+        let local_player_handles = self.p2p_session.local_player_handles();
+        self.connection_stats = (0..MAX_PLAYERS)
+            .filter(|handle| !local_player_handles.contains(handle))
+            .find_map(|handle| self.p2p_session.network_stats(handle).ok())
+            .map(|stats| {
+                let rollbacks_per_second = self.rollbacks.len();
+                let quality = if self.disconnect_deadline.is_some() {
+                    ConnectionQuality::Poor
+                } else {
+                    ConnectionQuality::new(
+                        stats.ping,
+                        stats.local_frames_behind,
+                        rollbacks_per_second,
+                    )
+                };
+                ConnectionStats {
+                    ping: stats.ping,
+                    local_frame_advantage: -stats.local_frames_behind,
+                    remote_frame_advantage: -stats.remote_frames_behind,
+                    rollbacks_per_second,
+                    quality,
+                }
+            });
+    }
+
     pub fn get_local_player_idx(&self) -> usize {
         //There should be only one.
         *self
@@ -60,6 +328,8 @@ impl NetplaySession {
         #[cfg(feature = "debug")]
         puffin::profile_function!();
 
+        self.handle_side_channel();
+
         let local_player_idx = self.get_local_player_idx();
         let sess = &mut self.p2p_session;
 
@@ -70,8 +340,38 @@ impl NetplaySession {
         }
 
         for event in sess.events() {
-            if let ggrs::GgrsEvent::Disconnected { addr } = event {
-                return Err(anyhow::anyhow!("Lost peer {:?}", addr));
+            match event {
+                ggrs::GgrsEvent::Disconnected { addr } => {
+                    return Err(anyhow::anyhow!("Lost peer {:?}", addr));
+                }
+                ggrs::GgrsEvent::DesyncDetected {
+                    frame,
+                    local_checksum,
+                    remote_checksum,
+                    addr,
+                } => {
+                    log::warn!(
+                        "Desync with {:?} at frame {} (local checksum {:x}, remote checksum {:x})",
+                        addr,
+                        frame,
+                        local_checksum,
+                        remote_checksum
+                    );
+                    self.desync_frame.get_or_insert(frame);
+                }
+                ggrs::GgrsEvent::NetworkInterrupted {
+                    addr,
+                    disconnect_timeout,
+                } => {
+                    log::warn!("Connection to {:?} interrupted", addr);
+                    self.disconnect_deadline =
+                        Some(Instant::now() + Duration::from_millis(disconnect_timeout as u64));
+                }
+                ggrs::GgrsEvent::NetworkResumed { addr } => {
+                    log::info!("Connection to {:?} resumed", addr);
+                    self.disconnect_deadline = None;
+                }
+                _ => {}
             }
         }
 
@@ -88,10 +388,21 @@ impl NetplaySession {
                         GgrsRequest::LoadGameState { cell, frame } => {
                             log::debug!("Loading (frame {:?})", frame);
                             self.game_state = cell.load().expect("ggrs state to load");
+                            self.rollbacks.push_back(Instant::now());
                         }
                         GgrsRequest::SaveGameState { cell, frame } => {
                             assert_eq!(self.game_state.frame, frame);
-                            cell.save(frame, Some(self.game_state.clone()), None);
+                            // Only the frames that are compared need a checksum
+                            let checksum = if frame % DESYNC_DETECTION_INTERVAL as i32 == 0 {
+                                self.game_state.checksum()
+                            } else {
+                                None
+                            };
+                            if let (Some(recorder), Some(checksum)) = (&mut self.recorder, checksum)
+                            {
+                                recorder.record_checksum(frame, checksum);
+                            }
+                            cell.save(frame, Some(self.game_state.clone()), checksum);
                         }
                         GgrsRequest::AdvanceFrame { inputs } => {
                             let is_replay = self.game_state.frame <= self.last_handled_frame;
@@ -99,13 +410,15 @@ impl NetplaySession {
                                 audio: None,
                                 video: None,
                             };
-                            self.game_state.advance(
-                                joypad_mapping.map(
-                                    [JoypadState(inputs[0].0), JoypadState(inputs[1].0)],
-                                    local_player_idx,
-                                ),
-                                if is_replay { no_buffers } else { buffers },
+                            let inputs = joypad_mapping.map(
+                                [JoypadState(inputs[0].0), JoypadState(inputs[1].0)],
+                                local_player_idx,
                             );
+                            if let Some(recorder) = &mut self.recorder {
+                                recorder.record_inputs(self.game_state.frame, inputs);
+                            }
+                            self.game_state
+                                .advance(inputs, if is_replay { no_buffers } else { buffers });
 
                             if !is_replay {
                                 //This is not a replay
@@ -128,6 +441,12 @@ impl NetplaySession {
             }
         }
 
+        if self.connection_stats_updated.elapsed() > CONNECTION_STATS_INTERVAL {
+            self.connection_stats_updated = Instant::now();
+            self.update_connection_stats();
+        }
+
+        let sess = &mut self.p2p_session;
         if sess.frames_ahead() > 0 {
             log::debug!(
                 "Frames ahead: {:?}, slowing down emulation",
@@ -142,3 +461,16 @@ impl NetplaySession {
         Ok(())
     }
 }
+
+impl Drop for NetplaySession {
+    fn drop(&mut self) {
+        if let Some(recorder) = self.recorder.take() {
+            let player_names = self.player_names.clone();
+            match recorder.save(self.p2p_session.confirmed_frame(), player_names) {
+                Ok(Some(path)) => log::info!("Saved replay to {:?}", path),
+                Ok(None) => {}
+                Err(e) => log::error!("Could not save the replay: {:?}", e),
+            }
+        }
+    }
+}
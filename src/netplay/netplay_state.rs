@@ -1,9 +1,11 @@
+use std::net::SocketAddr;
 use std::time::Instant;
 
 use anyhow::Result;
 use uuid::Uuid;
 
 use crate::{
+    audio::mixer::CHANNEL_COUNT,
     bundle::Bundle,
     emulation::{LocalNesState, NESBuffers, NesStateHandler},
     input::JoypadState,
@@ -11,8 +13,9 @@ use crate::{
 };
 
 use super::{
-    connecting_state::JoinOrHost, netplay_session::NetplaySession, ConnectingState, JoypadMapping,
-    StartMethod, StartState,
+    connecting_state::JoinOrHost, direct::DirectConnection, netplay_session::NetplaySession,
+    replay::ReplayPlayback, spectator_session::NetplaySpectatorSession, ConnectingState,
+    JoypadMapping, NetplayNesState, StartMethod, StartState,
 };
 
 pub enum NetplayState {
@@ -20,6 +23,8 @@ pub enum NetplayState {
     Connecting(Netplay<ConnectingState>),
     Connected(Netplay<Connected>),
     Resuming(Netplay<Resuming>),
+    Spectating(Netplay<NetplaySpectatorSession>),
+    Replaying(Netplay<ReplayPlayback>),
     Failed(Netplay<Failed>),
 }
 
@@ -39,7 +44,7 @@ impl NetplayState {
                 //No sound while connecting
                 if let Some(audio) = &mut buffers.audio {
                     for _ in 0..1000 {
-                        audio.push(0.0);
+                        audio.push([0.0; CHANNEL_COUNT]);
                     }
                 }
 
@@ -50,13 +55,15 @@ impl NetplayState {
                 //No sound while resuming
                 if let Some(audio) = &mut buffers.audio {
                     for _ in 0..1000 {
-                        audio.push(0.0);
+                        audio.push([0.0; CHANNEL_COUNT]);
                     }
                 }
 
                 netplay.advance()
             }
             Disconnected(netplay) => netplay.advance(joypad_state, buffers),
+            Spectating(netplay) => netplay.advance(buffers),
+            Replaying(netplay) => netplay.advance(buffers),
             Failed(netplay) => netplay.advance(),
         }
     }
@@ -81,12 +88,14 @@ impl<T> Netplay<T> {
 pub struct Connected {
     pub netplay_session: NetplaySession,
     session_id: String,
+    /// Set when connected without a signalling server
+    direct: Option<DirectConnection>,
     pub start_time: Instant,
 }
 
 pub struct Resuming {
     attempt1: ConnectingState,
-    attempt2: ConnectingState,
+    attempt2: Option<ConnectingState>,
 }
 impl Resuming {
     fn new(netplay: &mut Netplay<Connected>) -> Self {
@@ -98,10 +107,25 @@ impl Resuming {
                 game_state: netplay_session.last_confirmed_game_states[1].clone(),
                 session_id: session_id.clone(),
             })),
-            attempt2: ConnectingState::connect(StartMethod::Resume(StartState {
+            attempt2: Some(ConnectingState::connect(StartMethod::Resume(StartState {
                 game_state: netplay_session.last_confirmed_game_states[0].clone(),
                 session_id,
-            })),
+            }))),
+        }
+    }
+
+    /// Reconnects with a game state everyone agrees on
+    fn resync(netplay: &mut Netplay<Connected>, game_state: NetplayNesState) -> Self {
+        let start_state = StartState {
+            game_state,
+            session_id: netplay.state.session_id.clone(),
+        };
+        Self {
+            attempt1: ConnectingState::connect(match &netplay.state.direct {
+                Some(connection) => StartMethod::Direct(start_state, connection.clone()),
+                None => StartMethod::Resume(start_state),
+            }),
+            attempt2: None,
         }
     }
 }
@@ -113,16 +137,33 @@ pub fn get_netplay_id() -> String {
 }
 
 pub const MAX_ROOM_NAME_LEN: u8 = 4;
+pub const MAX_PLAYER_NAME_LEN: usize = 16;
+
+/// The name from the settings, or the name of the user if none has been set
+pub fn get_player_name() -> String {
+    Settings::current()
+        .player_name
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .or_else(|| std::env::var("USER").ok())
+        .or_else(|| std::env::var("USERNAME").ok())
+        .unwrap_or_else(|| "Player".to_string())
+        .chars()
+        .take(MAX_PLAYER_NAME_LEN)
+        .collect()
+}
 
 impl Netplay<LocalNesState> {
     pub fn new() -> Result<Self> {
-        Ok(Self {
-            state: LocalNesState::start_rom(
-                &Bundle::current().rom,
-                true,
-                Settings::current_mut().get_nes_region(),
-            )?,
-        })
+        let mut state = LocalNesState::start_rom(
+            &Bundle::current().rom,
+            true,
+            Settings::current_mut().get_nes_region(),
+        )?;
+        state.set_turbo_frames(*Settings::current_mut().input.get_turbo_frames());
+        Ok(Self { state })
     }
 
     pub fn host_game(self) -> Result<NetplayState> {
@@ -135,18 +176,56 @@ impl Netplay<LocalNesState> {
         self.join_or_host(&room_name, JoinOrHost::Host)
     }
 
+    /// Hosts a game that anyone can join from the lobby
+    pub fn host_public_game(self) -> Result<NetplayState> {
+        use rand::distributions::{Alphanumeric, DistString};
+
+        let room_name = Alphanumeric
+            .sample_string(&mut rand::thread_rng(), MAX_ROOM_NAME_LEN.into())
+            .to_uppercase();
+
+        self.join_or_host(&room_name, JoinOrHost::HostPublic)
+    }
+
+    pub fn host_direct(self, port: u16) -> Result<NetplayState> {
+        self.start_direct(DirectConnection::Host(port))
+    }
+
+    pub fn join_direct(self, addr: SocketAddr) -> Result<NetplayState> {
+        self.start_direct(DirectConnection::Join(addr))
+    }
+
+    /// Boots the netplay ROM in the region the player has picked, the other players have to pick the same one
+    fn start_netplay_rom() -> Result<LocalNesState> {
+        let region = Settings::current_mut().get_nes_region().clone();
+        LocalNesState::start_rom(&Bundle::current().netplay_rom, false, &region)
+    }
+
+    fn start_direct(self, connection: DirectConnection) -> Result<NetplayState> {
+        let netplay_rom = &Bundle::current().netplay_rom;
+        let session_id = format!("direct_{:x}", md5::compute(netplay_rom));
+        let nes_state = Self::start_netplay_rom()?;
+        Ok(self.start(StartMethod::Direct(
+            StartState {
+                game_state: super::NetplayNesState::new(nes_state),
+                session_id,
+            },
+            connection,
+        )))
+    }
+
     pub fn join_game(self, room_name: &str) -> Result<NetplayState> {
         self.join_or_host(&room_name.to_uppercase(), JoinOrHost::Join)
     }
 
+    pub fn watch_game(self, room_name: &str) -> Result<NetplayState> {
+        self.join_or_host(&room_name.to_uppercase(), JoinOrHost::Watch)
+    }
+
     fn join_or_host(self, room_name: &str, join_or_host: JoinOrHost) -> Result<NetplayState> {
         let netplay_rom = &Bundle::current().netplay_rom;
         let session_id = format!("{}_{:x}", room_name, md5::compute(netplay_rom));
-        let nes_state = LocalNesState::start_rom(
-            netplay_rom,
-            false,
-            Bundle::current().config.get_default_region(),
-        )?;
+        let nes_state = Self::start_netplay_rom()?;
         Ok(self.start(StartMethod::Start(
             StartState {
                 game_state: super::NetplayNesState::new(nes_state),
@@ -164,17 +243,18 @@ impl Netplay<LocalNesState> {
         // TODO: When resuming using this session id there might be collisions, but it's unlikely.
         //       Should be fixed though.
         let session_id = format!("{:x}", rom_hash);
-        let nes_state = LocalNesState::start_rom(
-            netplay_rom,
-            false,
-            Bundle::current().config.get_default_region(),
-        )?;
+        let nes_state = Self::start_netplay_rom()?;
         Ok(self.start(StartMethod::MatchWithRandom(StartState {
             game_state: super::NetplayNesState::new(nes_state),
             session_id,
         })))
     }
 
+    pub fn play_replay(self, playback: ReplayPlayback) -> NetplayState {
+        log::debug!("Playing replay of session {}", playback.replay.session_id);
+        NetplayState::Replaying(Netplay::from(playback))
+    }
+
     pub fn start(self, start_method: StartMethod) -> NetplayState {
         log::debug!("Starting: {:?}", start_method);
         NetplayState::Connecting(Netplay::from(ConnectingState::connect(start_method)))
@@ -198,18 +278,27 @@ impl Netplay<ConnectingState> {
         match self.state {
             ConnectingState::Connected(connected) => {
                 log::debug!("Connected! Starting netplay session");
+                let (session_id, direct) = match connected.start_method {
+                    StartMethod::Start(StartState { session_id, .. }, ..)
+                    | StartMethod::MatchWithRandom(StartState { session_id, .. })
+                    | StartMethod::Resume(StartState { session_id, .. }) => (session_id, None),
+                    StartMethod::Direct(StartState { session_id, .. }, connection) => {
+                        (session_id, Some(connection))
+                    }
+                };
                 NetplayState::Connected(Netplay {
                     state: Connected {
                         start_time: Instant::now(),
                         netplay_session: connected.state,
-                        session_id: match connected.start_method {
-                            StartMethod::Start(StartState { session_id, .. }, ..)
-                            | StartMethod::MatchWithRandom(StartState { session_id, .. })
-                            | StartMethod::Resume(StartState { session_id, .. }) => session_id,
-                        },
+                        session_id,
+                        direct,
                     },
                 })
             }
+            ConnectingState::Spectating(spectating) => {
+                log::debug!("Connected! Starting to watch");
+                NetplayState::Spectating(Netplay::from(spectating.state))
+            }
             ConnectingState::Failed(reason) => NetplayState::Failed(Netplay {
                 state: Failed { reason },
             }),
@@ -219,7 +308,21 @@ impl Netplay<ConnectingState> {
 }
 
 impl Netplay<Connected> {
-    pub fn resume(mut self) -> Netplay<Resuming> {
+    /// Tries to get back into the game after losing the other player
+    pub fn connection_lost(self) -> NetplayState {
+        if self.state.direct.is_some() {
+            // Without a signalling server there is no room to meet up in again
+            NetplayState::Failed(Netplay {
+                state: Failed {
+                    reason: "Lost the connection to the other player".to_string(),
+                },
+            })
+        } else {
+            NetplayState::Resuming(self.resume())
+        }
+    }
+
+    fn resume(mut self) -> Netplay<Resuming> {
         log::debug!(
             "Resuming netplay to one of the frames ({:?})",
             self.state
@@ -232,6 +335,11 @@ impl Netplay<Connected> {
         Netplay::from(Resuming::new(&mut self))
     }
 
+    fn resync(mut self, game_state: NetplayNesState) -> Netplay<Resuming> {
+        log::debug!("Resyncing netplay to frame {}", game_state.frame);
+        Netplay::from(Resuming::resync(&mut self, game_state))
+    }
+
     fn advance(
         mut self,
         joypad_state: [JoypadState; MAX_PLAYERS],
@@ -242,11 +350,14 @@ impl Netplay<Connected> {
 
         if let Some(joypad_mapping) = &mut netplay_session.game_state.joypad_mapping.clone() {
             match netplay_session.advance(joypad_state, joypad_mapping, buffers) {
-                Ok(_) => NetplayState::Connected(self),
+                Ok(_) => match netplay_session.resync_state.take() {
+                    Some(game_state) => NetplayState::Resuming(self.resync(game_state)),
+                    None => NetplayState::Connected(self),
+                },
                 Err(e) => {
                     log::error!("Resuming due to error: {:?}", e);
                     //TODO: Popup/info about the error? Or perhaps put the reason for the resume in the resume state below?
-                    NetplayState::Resuming(self.resume())
+                    self.connection_lost()
                 }
             }
         } else {
@@ -266,16 +377,14 @@ impl Netplay<Resuming> {
     fn advance(mut self) -> NetplayState {
         //log::trace!("Advancing Netplay<Resuming>");
         self.state.attempt1 = self.state.attempt1.advance();
-        self.state.attempt2 = self.state.attempt2.advance();
+        self.state.attempt2 = self.state.attempt2.map(ConnectingState::advance);
 
         if let ConnectingState::Connected(_) = &self.state.attempt1 {
             NetplayState::Connecting(Netplay {
                 state: self.state.attempt1,
             })
-        } else if let ConnectingState::Connected(_) = &self.state.attempt2 {
-            NetplayState::Connecting(Netplay {
-                state: self.state.attempt2,
-            })
+        } else if let Some(attempt2 @ ConnectingState::Connected(_)) = self.state.attempt2 {
+            NetplayState::Connecting(Netplay { state: attempt2 })
         } else {
             NetplayState::Resuming(self)
         }
@@ -287,6 +396,42 @@ impl Netplay<Resuming> {
     }
 }
 
+impl Netplay<NetplaySpectatorSession> {
+    fn advance(mut self, buffers: &mut NESBuffers) -> NetplayState {
+        match self.state.advance(buffers) {
+            Ok(true) => NetplayState::Spectating(self),
+            Ok(false) => {
+                //No sound while waiting for the host
+                if let Some(audio) = &mut buffers.audio {
+                    for _ in 0..1000 {
+                        audio.push([0.0; CHANNEL_COUNT]);
+                    }
+                }
+                NetplayState::Spectating(self)
+            }
+            Err(e) => {
+                log::error!("Stopped watching due to error: {:?}", e);
+                NetplayState::Failed(Netplay {
+                    state: Failed {
+                        reason: "Lost the connection to the host".to_string(),
+                    },
+                })
+            }
+        }
+    }
+}
+
+impl Netplay<ReplayPlayback> {
+    fn advance(mut self, buffers: &mut NESBuffers) -> NetplayState {
+        if self.state.advance(buffers) {
+            NetplayState::Replaying(self)
+        } else {
+            log::debug!("Replay finished");
+            NetplayState::Disconnected(self.disconnect())
+        }
+    }
+}
+
 impl Netplay<Failed> {
     fn advance(self) -> NetplayState {
         NetplayState::Failed(self)
@@ -0,0 +1,213 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bundle::Bundle,
+    emulation::{LocalNesState, NESBuffers, NesRegion, NesStateHandler},
+    input::JoypadState,
+    settings::MAX_PLAYERS,
+};
+
+use super::{rom_hash, NetplayNesState};
+
+/// Bumped whenever the replay format changes
+const REPLAY_VERSION: u32 = 1;
+const REPLAY_EXTENSION: &str = "replay";
+
+/// Everything needed to play a netplay session again
+#[derive(Serialize, Deserialize)]
+pub struct Replay {
+    pub version: u32,
+    pub rom_hash: String,
+    pub session_id: String,
+    /// When the session started, in milliseconds since the unix epoch
+    pub started_at: u128,
+    pub region: NesRegion,
+    pub player_names: [Option<String>; MAX_PLAYERS],
+    /// The player that recorded the replay
+    pub local_player: usize,
+    pub input_delay: usize,
+    /// The game state the session started from
+    pub start_state: Vec<u8>,
+    /// The confirmed inputs of every frame, as the NES saw them
+    pub inputs: Vec<[u16; MAX_PLAYERS]>,
+    /// The checksums the players compared to detect desyncs, by frame
+    pub checksums: BTreeMap<i32, u128>,
+}
+
+pub fn replays_dir() -> PathBuf {
+    Bundle::current().settings_path.join("replays")
+}
+
+/// The recorded replays, newest first
+pub fn list_replays() -> Vec<PathBuf> {
+    let mut replays: Vec<PathBuf> = fs::read_dir(replays_dir())
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == REPLAY_EXTENSION))
+                .collect()
+        })
+        .unwrap_or_default();
+    replays.sort();
+    replays.reverse();
+    replays
+}
+
+/// Records the confirmed inputs of a netplay session
+pub struct ReplayRecorder {
+    replay: Replay,
+}
+
+impl ReplayRecorder {
+    pub fn new(
+        session_id: &str,
+        game_state: &NetplayNesState,
+        local_player: usize,
+        input_delay: usize,
+    ) -> Result<Self> {
+        Ok(Self {
+            replay: Replay {
+                version: REPLAY_VERSION,
+                rom_hash: rom_hash(),
+                session_id: session_id.to_string(),
+                started_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis(),
+                region: game_state.region().clone(),
+                player_names: Default::default(),
+                local_player,
+                input_delay,
+                start_state: game_state.save_state()?,
+                inputs: Vec::new(),
+                checksums: BTreeMap::new(),
+            },
+        })
+    }
+
+    pub fn record_inputs(&mut self, frame: i32, inputs: [JoypadState; MAX_PLAYERS]) {
+        // After a rollback the frames are played again with the right inputs
+        self.replay.inputs.truncate(frame as usize);
+        self.replay.inputs.push(inputs.map(|input| input.0));
+    }
+
+    pub fn record_checksum(&mut self, frame: i32, checksum: u128) {
+        self.replay.checksums.insert(frame, checksum);
+    }
+
+    /// Writes the replay, leaving out the frames that were never confirmed
+    pub fn save(
+        mut self,
+        confirmed_frame: i32,
+        player_names: [Option<String>; MAX_PLAYERS],
+    ) -> Result<Option<PathBuf>> {
+        let confirmed_frames = (confirmed_frame + 1).max(0);
+        self.replay.inputs.truncate(confirmed_frames as usize);
+        self.replay
+            .checksums
+            .retain(|frame, _| *frame < confirmed_frames);
+        if self.replay.inputs.is_empty() {
+            return Ok(None);
+        }
+        self.replay.player_names = player_names;
+
+        let dir = replays_dir();
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!(
+            "{}-{}.{REPLAY_EXTENSION}",
+            Bundle::current().config.name,
+            self.replay.started_at
+        ));
+        bincode::serialize_into(BufWriter::new(File::create(&path)?), &self.replay)?;
+        Ok(Some(path))
+    }
+}
+
+/// Plays a recorded session offline
+pub struct ReplayPlayback {
+    pub replay: Replay,
+    pub game_state: NetplayNesState,
+    /// The first frame where the game state didn't match the recording
+    pub desync_frame: Option<i32>,
+}
+
+impl ReplayPlayback {
+    pub fn load(path: &Path) -> Result<Self> {
+        let replay: Replay = bincode::deserialize_from(BufReader::new(File::open(path)?))?;
+        if replay.version != REPLAY_VERSION {
+            bail!("Unsupported replay version {}", replay.version);
+        }
+        if replay.rom_hash != rom_hash() {
+            bail!("The replay was recorded with a different game");
+        }
+
+        let mut nes_state =
+            LocalNesState::start_rom(&Bundle::current().netplay_rom, false, &replay.region)?;
+        nes_state.load_state(&replay.start_state)?;
+        Ok(Self {
+            replay,
+            game_state: NetplayNesState::new(nes_state),
+            desync_frame: None,
+        })
+    }
+
+    pub fn frames(&self) -> usize {
+        self.replay.inputs.len()
+    }
+
+    /// Plays the next frame, returns `false` when there are no frames left
+    pub fn advance(&mut self, buffers: &mut NESBuffers) -> bool {
+        let frame = self.game_state.frame;
+        let Some(inputs) = self.replay.inputs.get(frame as usize) else {
+            return false;
+        };
+        if let Some(checksum) = self.replay.checksums.get(&frame) {
+            if self.desync_frame.is_none() && self.game_state.checksum() != Some(*checksum) {
+                log::warn!("The replay differs from the recording at frame {}", frame);
+                self.desync_frame = Some(frame);
+            }
+        }
+        self.game_state.advance(inputs.map(JoypadState), buffers);
+        self.game_state.frame += 1;
+        true
+    }
+}
+
+/// Plays a replay as fast as possible without a window and reports if it matches the recording
+pub fn run_headless(path: &Path) -> Result<()> {
+    let mut playback = ReplayPlayback::load(path)?;
+    let replay = &playback.replay;
+    println!("Session: {}", replay.session_id);
+    for (handle, name) in replay.player_names.iter().enumerate() {
+        let name = name.as_deref().unwrap_or("Unknown");
+        let recorder = if handle == replay.local_player {
+            " (recorded the replay)"
+        } else {
+            ""
+        };
+        println!("Player {}: {name}{recorder}", handle + 1);
+    }
+    println!("Input delay: {} frames", replay.input_delay);
+
+    let checksums = replay.checksums.len();
+    let mut no_buffers = NESBuffers {
+        audio: None,
+        video: None,
+    };
+    while playback.advance(&mut no_buffers) {}
+    println!("Played {} frames", playback.frames());
+
+    match playback.desync_frame {
+        Some(frame) => bail!("The game state differs from the recording at frame {frame}"),
+        None => {
+            println!("All {checksums} checksums match the recording");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use matchbox_socket::{PeerId, PeerState, WebRtcChannel, WebRtcSocket};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    compatibility::Compatibility, connecting_state::GGRSConfiguration, direct::TcpSideChannel,
+};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SideChannelMessage {
+    /// Tells a newly connected peer if we are here to play or to watch
+    Role { spectator: bool },
+    /// The game is already running and can't take any more peers
+    AlreadyStarted,
+    /// The game state spectators start watching from
+    SpectatorState { frame: i32, state: Vec<u8> },
+    /// Asks the host to send its game state
+    ResyncRequest,
+    /// The game state of the host that everyone should continue from
+    ResyncState { frame: i32, state: Vec<u8> },
+    /// The game state was received
+    ResyncAck,
+    /// The UDP port GGRS is listening on, when connected directly
+    DirectHello { udp_port: u16 },
+    /// The display name of the player with this handle
+    PlayerName { handle: usize, name: String },
+    /// A line of text chat from the player with this handle
+    Chat { handle: usize, text: String },
+    /// Measures the round trip time
+    Ping { id: u32 },
+    /// The answer to a `Ping`
+    Pong { id: u32 },
+    /// The GGRS configuration the host picked for the session
+    SessionSettings(GGRSConfiguration),
+    /// The game state of the host that the session starts from
+    InitialState { state: Vec<u8> },
+    /// What the game is running with, checked before the session starts
+    Compatibility(Compatibility),
+}
+
+pub fn send_message(channel: &mut WebRtcChannel, peer: PeerId, message: &SideChannelMessage) {
+    match bincode::serialize(message) {
+        Ok(packet) => channel.send(packet.into_boxed_slice(), peer),
+        Err(e) => log::error!("Could not serialize {:?}: {:?}", message, e),
+    }
+}
+
+/// A reliable connection to the other peers for everything that isn't inputs
+pub enum SideChannel {
+    WebRtc {
+        // Kept around to keep the connection to the peers open
+        socket: WebRtcSocket,
+        channel: WebRtcChannel,
+    },
+    Tcp(TcpSideChannel),
+}
+
+impl SideChannel {
+    /// The peers that connected or disconnected since the last call
+    pub fn update_peers(&mut self) -> Vec<(PeerId, PeerState)> {
+        match self {
+            SideChannel::WebRtc { socket, .. } => socket.update_peers(),
+            SideChannel::Tcp(tcp) => tcp.update_peers(),
+        }
+    }
+
+    pub fn connected_peers(&self) -> Vec<PeerId> {
+        match self {
+            SideChannel::WebRtc { socket, .. } => socket.connected_peers().collect(),
+            SideChannel::Tcp(tcp) => tcp.connected_peers(),
+        }
+    }
+
+    pub fn send(&mut self, peer: PeerId, message: &SideChannelMessage) {
+        match self {
+            SideChannel::WebRtc { channel, .. } => send_message(channel, peer, message),
+            SideChannel::Tcp(tcp) => tcp.send(message),
+        }
+    }
+
+    pub fn send_to_all(&mut self, message: &SideChannelMessage) {
+        for peer in self.connected_peers() {
+            self.send(peer, message);
+        }
+    }
+
+    pub fn receive(&mut self) -> Vec<(PeerId, SideChannelMessage)> {
+        let packets = match self {
+            SideChannel::WebRtc { channel, .. } => channel.receive(),
+            SideChannel::Tcp(tcp) => tcp.receive(),
+        };
+        decode(packets)
+    }
+}
+
+pub fn decode(packets: Vec<(PeerId, Box<[u8]>)>) -> Vec<(PeerId, SideChannelMessage)> {
+    packets
+        .into_iter()
+        .filter_map(
+            |(peer, packet)| match bincode::deserialize::<SideChannelMessage>(&packet) {
+                Ok(message) => Some((peer, message)),
+                Err(e) => {
+                    log::warn!("Could not read message from {:?}: {:?}", peer, e);
+                    None
+                }
+            },
+        )
+        .collect()
+}
@@ -0,0 +1,89 @@
+use ggrs::{GgrsError, GgrsEvent, GgrsRequest, SpectatorSession};
+
+use crate::{
+    emulation::{NESBuffers, NesStateHandler},
+    input::JoypadState,
+};
+
+use super::{
+    connecting_state::StartMethod,
+    netplay_session::GGRSConfig,
+    side_channel::{SideChannel, SideChannelMessage},
+    NetplayNesState,
+};
+
+/// Watches a game by running the confirmed inputs the host sends
+pub struct NetplaySpectatorSession {
+    session: SpectatorSession<GGRSConfig>,
+    side_channel: SideChannel,
+    pub game_state: NetplayNesState,
+    /// The host has sent the game state to start watching from
+    pub watching: bool,
+}
+
+impl NetplaySpectatorSession {
+    pub fn new(
+        start_method: StartMethod,
+        session: SpectatorSession<GGRSConfig>,
+        side_channel: SideChannel,
+    ) -> Self {
+        let game_state = match start_method {
+            StartMethod::Start(start_state, ..)
+            | StartMethod::Resume(start_state)
+            | StartMethod::MatchWithRandom(start_state)
+            | StartMethod::Direct(start_state, _) => start_state.game_state,
+        };
+        Self {
+            session,
+            side_channel,
+            game_state,
+            watching: false,
+        }
+    }
+
+    /// Returns `true` if a frame was emulated
+    pub fn advance(&mut self, buffers: &mut NESBuffers) -> anyhow::Result<bool> {
+        self.side_channel.update_peers();
+        for (_, message) in self.side_channel.receive() {
+            // The rest is meant for the players
+            if let SideChannelMessage::SpectatorState { frame, state } = message {
+                log::debug!("Got the game state to watch from (frame {})", frame);
+                self.game_state.load_state(&state)?;
+                self.game_state.frame = frame;
+                self.watching = true;
+            }
+        }
+
+        self.session.poll_remote_clients();
+        for event in self.session.events() {
+            if let GgrsEvent::Disconnected { addr } = event {
+                return Err(anyhow::anyhow!("Lost host {:?}", addr));
+            }
+        }
+
+        if !self.watching {
+            return Ok(false);
+        }
+
+        let mut advanced = false;
+        match self.session.advance_frame() {
+            Ok(requests) => {
+                for request in requests {
+                    if let GgrsRequest::AdvanceFrame { inputs } = request {
+                        self.game_state.advance(
+                            [JoypadState(inputs[0].0), JoypadState(inputs[1].0)],
+                            buffers,
+                        );
+                        self.game_state.frame += 1;
+                        advanced = true;
+                    }
+                }
+            }
+            Err(GgrsError::PredictionThreshold) => {
+                // Waiting for the inputs of the next frame
+            }
+            Err(e) => log::warn!("Frame {} skipped: {:?}", self.game_state.frame, e),
+        }
+        Ok(advanced)
+    }
+}
@@ -62,6 +62,70 @@ pub struct Settings {
     pub netplay_id: Option<String>,
     pub save_state: Option<String>,
     nes_region: Option<NesRegion>,
+    #[serde(default)]
+    focus_loss: Option<FocusLossAction>,
+    /// Show the buttons each player is pressing on top of the game
+    #[serde(default)]
+    pub show_input_display: bool,
+    /// What other players see you as when playing online
+    #[cfg(feature = "netplay")]
+    #[serde(default)]
+    pub player_name: Option<String>,
+    /// Show how the connection is doing on top of the game when playing online
+    #[cfg(feature = "netplay")]
+    #[serde(default)]
+    pub show_netplay_hud: bool,
+    /// The input delay of the games you host
+    #[cfg(feature = "netplay")]
+    #[serde(default)]
+    pub netplay_input_delay: NetplayInputDelay,
+    /// Where you are, shown next to the public games you host
+    #[cfg(feature = "netplay")]
+    #[serde(default)]
+    pub netplay_region: Option<String>,
+}
+
+/// How many frames inputs are delayed by when playing online
+#[cfg(feature = "netplay")]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, Hash, PartialEq)]
+pub enum NetplayInputDelay {
+    /// Whatever the netplay server configuration says
+    #[default]
+    Configured,
+    /// Based on the round trip time to the other player
+    Auto,
+    Fixed(u8),
+}
+
+#[cfg(feature = "netplay")]
+impl std::fmt::Display for NetplayInputDelay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetplayInputDelay::Configured => write!(f, "Default"),
+            NetplayInputDelay::Auto => write!(f, "Auto"),
+            NetplayInputDelay::Fixed(frames) => write!(f, "{frames} frames"),
+        }
+    }
+}
+
+/// What to do when the window loses focus
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, Hash, PartialEq)]
+pub enum FocusLossAction {
+    #[default]
+    KeepRunning,
+    Mute,
+    /// Pause the emulation and show the menu (only when not playing online)
+    Pause,
+}
+
+impl std::fmt::Display for FocusLossAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FocusLossAction::KeepRunning => write!(f, "Keep running"),
+            FocusLossAction::Mute => write!(f, "Mute"),
+            FocusLossAction::Pause => write!(f, "Pause"),
+        }
+    }
 }
 
 impl Settings {
@@ -137,4 +201,14 @@ impl Settings {
         self.nes_region
             .get_or_insert_with(|| Bundle::current().config.get_default_region().clone())
     }
+
+    pub fn get_focus_loss(&mut self) -> &mut FocusLossAction {
+        self.focus_loss.get_or_insert_with(|| {
+            Bundle::current()
+                .config
+                .default_settings
+                .focus_loss
+                .unwrap_or_default()
+        })
+    }
 }
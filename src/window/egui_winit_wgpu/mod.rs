@@ -9,7 +9,7 @@ use gui::EguiRenderer;
 use wgpu::{PresentMode, TextureViewDescriptor};
 use winit::window::Window;
 
-use crate::bundle::Bundle;
+use crate::{bundle::Bundle, emulation::pacing::FramePacing};
 
 pub mod texture;
 
@@ -65,7 +65,8 @@ impl Renderer {
             .find(|f| !f.is_srgb())
             .unwrap_or(surface_caps.formats[0]);
 
-        let present_mode = if Bundle::current().config.enable_vsync {
+        let config = &Bundle::current().config;
+        let present_mode = if config.enable_vsync || config.frame_pacing == FramePacing::Vsync {
             PresentMode::AutoVsync
         } else {
             [
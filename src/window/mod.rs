@@ -1,7 +1,4 @@
-use crate::{
-    input::keys::{KeyCode, Modifiers},
-    Size,
-};
+use crate::Size;
 use anyhow::Result;
 use winit::event_loop::EventLoop;
 
@@ -9,7 +6,6 @@ pub mod egui_winit_wgpu;
 mod winit_impl;
 
 pub trait Fullscreen {
-    fn check_and_set_fullscreen(&self, key_mod: Modifiers, key_code: KeyCode) -> bool;
     fn toggle_fullscreen(&self);
     fn is_fullscreen(&self) -> bool;
 }
@@ -1,33 +1,10 @@
-use crate::{
-    input::keys::{KeyCode, Modifiers},
-    integer_scaling::MINIMUM_INTEGER_SCALING_SIZE,
-};
+use crate::integer_scaling::MINIMUM_INTEGER_SCALING_SIZE;
 
 use super::Fullscreen;
 
 mod conversions;
 
 impl Fullscreen for winit::window::Window {
-    fn check_and_set_fullscreen(&self, key_mod: Modifiers, key_code: KeyCode) -> bool {
-        #[cfg(target_os = "macos")]
-        if key_mod.contains(Modifiers::LOGO)
-            && (key_code == KeyCode::KeyF || key_code == KeyCode::Enter)
-        {
-            self.toggle_fullscreen();
-            return true;
-        }
-
-        #[cfg(not(target_os = "macos"))]
-        if (key_mod.contains(Modifiers::ALT) && key_code == KeyCode::Enter)
-            || key_code == KeyCode::F11
-        {
-            self.toggle_fullscreen();
-            return true;
-        };
-
-        false
-    }
-
     fn toggle_fullscreen(&self) {
         let window = self;
         #[cfg(target_os = "macos")]